@@ -0,0 +1,378 @@
+use crate::{error::NixError, gcroot::store_paths, nix::Env, source::EnvSource};
+use anyhow::{anyhow, Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Files of a dev shell that always invalidate the cache when they change.
+const SHELL_FILES: [&str; 4] = ["flake.nix", "flake.lock", "shell.nix", "default.nix"];
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CacheEntry<E> {
+    inputs_hash: String,
+    /// The store paths of the env that existed when it was cached.
+    #[serde(default)]
+    store_paths: Vec<PathBuf>,
+    env: E,
+}
+
+/// On-disk cache of `nix print-dev-env --json` results.
+/// One entry is kept per dev shell path, it is invalidated when the hash of
+/// the watched files differs from the one stored with the entry, or when
+/// its store paths were garbage collected.
+/// Remote flakes without a locked revision can change at any time, they are
+/// always evaluated and their entry is only used when nix times out.
+pub struct Cache {
+    file: PathBuf,
+    inputs_hash: String,
+    locked: bool,
+}
+
+impl Cache {
+    /// `nix_args` are part of the inputs, changing them invalidates the entry.
+    pub fn new(path: Option<&str>, nix_args: &[String], watch: &[PathBuf]) -> Result<Cache, Error> {
+        let shell_dir = local_dir(path);
+
+        let mut watch_files: Vec<PathBuf> = Vec::new();
+        if let Some(dir) = &shell_dir {
+            watch_files.extend(SHELL_FILES.iter().map(|f| dir.join(f)));
+        }
+        watch_files.extend_from_slice(watch);
+
+        Ok(Cache {
+            file: cache_dir()?.join(shell_key(path)),
+            inputs_hash: hash_inputs(nix_args, &watch_files)?,
+            locked: shell_dir.is_some() || is_locked(path.unwrap_or_default()),
+        })
+    }
+
+    /// Returns the cached env if it exists, the watched files haven't changed
+    /// and its store paths still exist.
+    pub fn load(&self) -> Option<Env> {
+        self.load_entry()
+            .filter(|_| self.locked)
+            .filter(|entry| entry.inputs_hash == self.inputs_hash)
+            .filter(|entry| entry.store_paths.iter().all(|p| p.exists()))
+            .map(|entry| entry.env)
+    }
+
+    /// Returns the cached env even if the watched files have changed.
+    pub fn load_stale(&self) -> Option<Env> {
+        self.load_entry().map(|entry| entry.env)
+    }
+
+    fn load_entry(&self) -> Option<CacheEntry<Env>> {
+        let file = File::open(&self.file).ok()?;
+        serde_json::from_reader(BufReader::new(file)).ok()
+    }
+
+    pub fn store(&self, env: &Env) -> Result<(), Error> {
+        let entry = CacheEntry {
+            inputs_hash: self.inputs_hash.clone(),
+            store_paths: store_paths(env),
+            env,
+        };
+
+        write_json(&self.file, &entry)
+    }
+
+    /// Returns the cached env, or gets it from the source and caches it.
+    /// If the source times out, a stale env is used if there is one.
+    pub fn get_or_insert(&self, source: &dyn EnvSource, refresh: bool) -> Result<Env, Error> {
+        if !refresh {
+            if let Some(env) = self.load() {
+                return Ok(env);
+            }
+        }
+
+        let env = match source.get_env() {
+            Ok(env) => env,
+            Err(e) => match (e.downcast_ref::<NixError>(), self.load_stale()) {
+                (Some(NixError::Timeout { .. }), Some(env)) => {
+                    eprintln!("warning: {}, using the last cached env", e);
+                    return Ok(env);
+                }
+                _ => return Err(e),
+            },
+        };
+
+        if let Err(e) = self.store(&env) {
+            eprintln!("warning: failed to cache env: {:#}", e);
+        }
+
+        Ok(env)
+    }
+}
+
+/// Writes to a temporary file first so a concurrent reader never sees a partial file.
+pub fn write_json<T: Serialize>(file: &Path, value: &T) -> Result<(), Error> {
+    let dir = file
+        .parent()
+        .ok_or_else(|| anyhow!("invalid file path: {}", file.display()))?;
+    fs::create_dir_all(dir).with_context(|| format!("failed to create dir: {}", dir.display()))?;
+
+    let tmp = tempfile::NamedTempFile::new_in(dir).context("failed to create temporary file")?;
+    let mut writer = BufWriter::new(tmp.as_file());
+    serde_json::to_writer(&mut writer, value)
+        .with_context(|| format!("failed to serialize {}", file.display()))?;
+    writer
+        .flush()
+        .with_context(|| format!("failed to write {}", file.display()))?;
+    drop(writer);
+
+    tmp.persist(file)
+        .with_context(|| format!("failed to write {}", file.display()))?;
+
+    Ok(())
+}
+
+/// Stable identifier of a dev shell path, used to name its cache entry and gc roots.
+pub fn shell_key(path: Option<&str>) -> String {
+    let mut key = Sha256::new();
+    if let Some(dir) = local_dir(path) {
+        key.update(dir.as_os_str().as_encoded_bytes());
+    }
+    key.update([0]);
+    key.update(path.unwrap_or_default());
+
+    to_hex(&key.finalize())
+}
+
+/// `$XDG_CACHE_HOME/nix-dev-env`, falling back to `$HOME/.cache/nix-dev-env`.
+pub fn cache_dir() -> Result<PathBuf, Error> {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = env::var_os("HOME").context("neither XDG_CACHE_HOME nor HOME is set")?;
+            Path::new(&home).join(".cache")
+        }
+    };
+
+    Ok(base.join("nix-dev-env"))
+}
+
+/// Resolves the local directory of a flake reference like `.`, `path:./foo#bar`
+/// or `./foo#devShells.x86_64-linux.default`, or of a nix file like `./shell.nix`.
+/// Returns None for remote flakes.
+pub fn local_dir(path: Option<&str>) -> Option<PathBuf> {
+    let path = path.unwrap_or(".");
+    let path = path.split_once('#').map_or(path, |(p, _)| p);
+    let path = path.strip_prefix("path:").unwrap_or(path);
+    let path = if path.is_empty() { "." } else { path };
+
+    let path = fs::canonicalize(path).ok()?;
+    if path.is_file() {
+        path.parent().map(Path::to_path_buf)
+    } else {
+        Some(path).filter(|p| p.is_dir())
+    }
+}
+
+/// Whether a remote flake reference pins a revision, like `github:owner/repo/<rev>`
+/// or `git+https://host/repo?rev=<rev>`.
+fn is_locked(flake_ref: &str) -> bool {
+    let flake_ref = flake_ref.split_once('#').map_or(flake_ref, |(r, _)| r);
+    let (url, query) = flake_ref.split_once('?').unwrap_or((flake_ref, ""));
+
+    if query
+        .split('&')
+        .any(|param| param.starts_with("rev=") || param.starts_with("narHash="))
+    {
+        return true;
+    }
+
+    let is_rev = |s: &str| s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit());
+    match url.split_once(':') {
+        Some(("github" | "gitlab" | "sourcehut", repo)) => {
+            repo.split('/').nth(2).is_some_and(is_rev)
+        }
+        _ => false,
+    }
+}
+
+fn hash_inputs(nix_args: &[String], files: &[PathBuf]) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+
+    for arg in nix_args {
+        hasher.update(arg);
+        hasher.update([0]);
+    }
+    hasher.update([0]);
+
+    for file in files {
+        hasher.update(file.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+
+        match fs::read(file) {
+            Ok(contents) => {
+                hasher.update([1]);
+                hasher.update(&contents);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => hasher.update([0]),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", file.display()));
+            }
+        }
+    }
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::fake::FakeSource;
+
+    fn test_env() -> Env {
+        serde_json::from_str(
+            r#"
+            {
+                "bashFunctions": { "func1": "body1" },
+                "variables": {
+                    "var1": { "type": "exported", "value": "value1"}
+                }
+            }
+        "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cache_invalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let watched = dir.path().join("watched");
+        fs::write(&watched, "a").unwrap();
+
+        let cache = Cache {
+            file: dir.path().join("cache").join("entry"),
+            inputs_hash: hash_inputs(&[], std::slice::from_ref(&watched)).unwrap(),
+            locked: true,
+        };
+
+        assert!(cache.load().is_none(), "empty cache returned an env");
+
+        cache.store(&test_env()).unwrap();
+        let env = cache.load().expect("stored env wasn't loaded");
+        assert!(env.variables.contains(&"var1".to_string()));
+        assert!(env.bash_functions.contains(&"func1".to_string()));
+
+        fs::write(&watched, "b").unwrap();
+        let cache = Cache {
+            file: cache.file,
+            inputs_hash: hash_inputs(&[], &[watched]).unwrap(),
+            locked: true,
+        };
+
+        assert!(cache.load().is_none(), "stale cache entry was loaded");
+        assert!(
+            cache.load_stale().is_some(),
+            "stale cache entry was removed"
+        );
+    }
+
+    #[test]
+    fn test_deleted_store_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("store");
+        let out = store.join("0a5ybwqb5rn2l3m6ng8rkzs6j3dsb2qi-nix-shell");
+        fs::create_dir_all(&out).unwrap();
+        let env: Env = serde_json::from_value(serde_json::json!({
+            "bashFunctions": {},
+            "variables": {
+                "NIX_STORE": { "type": "exported", "value": store },
+                "out": { "type": "exported", "value": out },
+            }
+        }))
+        .unwrap();
+
+        let cache = Cache {
+            file: dir.path().join("entry"),
+            inputs_hash: String::new(),
+            locked: true,
+        };
+        cache.store(&env).unwrap();
+        assert!(cache.load().is_some(), "stored env wasn't loaded");
+
+        fs::remove_dir(&out).unwrap();
+        assert!(cache.load().is_none(), "env with deleted paths was loaded");
+        assert!(cache.load_stale().is_some());
+    }
+
+    #[test]
+    fn test_unlocked_remote() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache {
+            file: dir.path().join("entry"),
+            inputs_hash: String::new(),
+            locked: false,
+        };
+        cache.store(&test_env()).unwrap();
+
+        assert!(cache.load().is_none(), "unlocked remote flake was cached");
+        assert!(cache.load_stale().is_some());
+    }
+
+    #[test]
+    fn test_is_locked() {
+        assert!(!is_locked("github:me/tools"));
+        assert!(!is_locked("github:me/tools/main#ci"));
+        assert!(is_locked(
+            "github:me/tools/0123456789abcdef0123456789abcdef01234567#ci"
+        ));
+        assert!(!is_locked("git+https://example.com/tools.git?ref=main"));
+        assert!(is_locked(
+            "git+https://example.com/tools.git?ref=main&rev=0123456789abcdef0123456789abcdef01234567"
+        ));
+        assert!(!is_locked("nixpkgs#hello"));
+    }
+
+    #[test]
+    fn test_local_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_str = dir.path().to_str().unwrap();
+        let canonical = fs::canonicalize(dir.path()).unwrap();
+
+        assert_eq!(local_dir(Some(dir_str)), Some(canonical.clone()));
+        assert_eq!(
+            local_dir(Some(&format!("path:{}#devShells.x86_64-linux.ci", dir_str))),
+            Some(canonical)
+        );
+        assert_eq!(local_dir(Some("github:NixOS/nixpkgs")), None);
+    }
+
+    #[test]
+    fn test_get_or_insert() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache {
+            file: dir.path().join("entry"),
+            inputs_hash: String::new(),
+            locked: true,
+        };
+
+        let json = r#"{ "bashFunctions": {}, "variables": {} }"#;
+        let source = FakeSource::new(vec![Ok(json), Err("evaluation failed"), Ok(json)]);
+
+        assert!(cache.get_or_insert(&source, false).is_ok());
+        assert!(cache.get_or_insert(&source, false).is_ok());
+        assert_eq!(source.calls.get(), 1, "cached env wasn't used");
+
+        assert!(cache.get_or_insert(&source, true).is_err());
+        assert_eq!(source.calls.get(), 2, "refresh didn't query the source");
+
+        assert!(cache.get_or_insert(&source, false).is_ok());
+        assert_eq!(
+            source.calls.get(),
+            2,
+            "failed refresh dropped the cached env"
+        );
+    }
+}
@@ -1,10 +1,89 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fs};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::{
+    cache::local_dir, merge::ConflictResolution, pattern::Patterns, shell::PathPrecedence,
+};
+
+/// path_vars, not_path_vars, variables and the entries in paths are patterns,
+/// see `Patterns` for their syntax.
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Config {
-    pub path_vars: Vec<String>,
-    pub paths: HashMap<String, Vec<String>>,
-    pub variables: Vec<String>,
+    pub path_vars: Patterns,
+    /// Also treat variables whose values look like lists of absolute paths as path_vars.
+    #[serde(default)]
+    pub detect_path_vars: bool,
+    /// Variables that are never treated as path_vars by the built-in table or detection.
+    #[serde(default)]
+    pub not_path_vars: Patterns,
+    /// Separators of list variables that aren't `:` separated, e.g. `" "` for NIX_CFLAGS_COMPILE.
+    /// Their entries are filtered with paths and merged like path_vars.
+    #[serde(default)]
+    pub separators: HashMap<String, String>,
+    pub paths: HashMap<String, Patterns>,
+    pub variables: Patterns,
+    /// Extra arguments passed verbatim to nix print-dev-env.
+    #[serde(default)]
+    pub nix_args: Vec<String>,
+    /// How variables that differ between multiple dev shells are resolved.
+    #[serde(default)]
+    pub on_conflict: Option<ConflictResolution>,
+    /// Seconds after which nix is killed, like --timeout.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Whether the shellHook of a project is run, by project directory or flake reference.
+    #[serde(default)]
+    pub shell_hooks: HashMap<String, bool>,
+    /// Variables kept from the environment with --pure, besides HOME, USER, TERM and DISPLAY.
+    #[serde(default)]
+    pub keep: Vec<String>,
+    /// How path variables are combined with the inherited value, prepend if not set.
+    #[serde(default)]
+    pub path_precedence: HashMap<String, PathPrecedence>,
+}
+
+impl Config {
+    /// Whether the shellHook of the dev shell at `path` is allowed, None if the config
+    /// doesn't mention the project.
+    pub fn shell_hook_allowed(&self, path: Option<&str>) -> Option<bool> {
+        let dir = local_dir(path);
+
+        self.shell_hooks
+            .iter()
+            .find(|(project, _)| {
+                path.unwrap_or(".") == project.as_str()
+                    || dir.is_some() && fs::canonicalize(project).ok() == dir
+            })
+            .map(|(_, allowed)| *allowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_hook_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("project");
+        fs::create_dir(&project).unwrap();
+        fs::write(project.join("flake.nix"), "{}").unwrap();
+
+        let config = Config {
+            shell_hooks: HashMap::from([
+                (project.to_str().unwrap().to_string(), false),
+                ("github:me/tools".to_string(), true),
+            ]),
+            ..Config::default()
+        };
+
+        let flake = format!("{}#ci", project.join("flake.nix").display());
+        assert_eq!(config.shell_hook_allowed(Some(&flake)), Some(false));
+        assert_eq!(
+            config.shell_hook_allowed(Some("github:me/tools")),
+            Some(true)
+        );
+        assert_eq!(config.shell_hook_allowed(Some("github:me/other")), None);
+    }
 }
@@ -0,0 +1,183 @@
+use core::fmt;
+use std::time::Duration;
+
+use anyhow::Error;
+
+/// Why nix failed to produce a dev env.
+/// Every variant has its own process exit code so wrapper scripts can react to it.
+#[derive(Debug)]
+pub enum NixError {
+    /// nix isn't installed or not on PATH.
+    NotFound,
+    /// The installed nix doesn't support `print-dev-env --json`.
+    TooOld {
+        version: String,
+        required: (u32, u32),
+    },
+    /// The flake or its flake.nix doesn't exist.
+    FlakeNotFound {
+        stderr: Option<String>,
+        hint: Option<&'static str>,
+    },
+    /// Evaluating the dev shell failed.
+    Evaluation {
+        stderr: Option<String>,
+        hint: Option<&'static str>,
+    },
+    /// Building an input of the dev shell failed.
+    Build { stderr: Option<String> },
+    /// The json nix printed isn't in the expected format.
+    UnsupportedSchema(serde_json::Error),
+    /// nix printed something that isn't UTF-8.
+    NonUtf8Output,
+    /// nix ran longer than the timeout and was killed.
+    Timeout { timeout: Duration },
+    /// nix was killed because of SIGINT or SIGTERM.
+    Interrupted,
+}
+
+impl NixError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            NixError::NotFound => 3,
+            NixError::TooOld { .. } => 4,
+            NixError::FlakeNotFound { .. } => 5,
+            NixError::Evaluation { .. } => 6,
+            NixError::Build { .. } => 7,
+            NixError::UnsupportedSchema(_) => 8,
+            NixError::NonUtf8Output => 9,
+            NixError::Timeout { .. } => 10,
+            NixError::Interrupted => 130,
+        }
+    }
+
+    /// Classifies a failed nix call by its stderr.
+    /// `show_stderr` is false if the output was already shown to the user.
+    pub fn from_stderr(stderr: &str, show_stderr: bool) -> NixError {
+        let shown = show_stderr.then(|| stderr.trim_end().to_string());
+
+        if stderr.contains("flake.nix' does not exist") {
+            NixError::FlakeNotFound {
+                stderr: shown,
+                hint: Some("did you forget to `git add` flake.nix?"),
+            }
+        } else if stderr.contains("does not contain a 'flake.nix'")
+            || stderr.contains("cannot find flake")
+        {
+            NixError::FlakeNotFound {
+                stderr: shown,
+                hint: None,
+            }
+        } else if stderr.contains("builder for '")
+            || stderr.contains("Cannot build '")
+            || stderr.contains("dependencies couldn't be built")
+        {
+            NixError::Build { stderr: shown }
+        } else {
+            let hint = if stderr.contains("is not tracked by Git") {
+                Some("did you forget to `git add` the new files?")
+            } else if stderr.contains("does not provide attribute") {
+                Some("`nix-dev-env list-shells` lists the available devShells")
+            } else if stderr.contains("unable to download")
+                || stderr.contains("Could not resolve host")
+            {
+                Some("without network access, --from-profile enters a saved profile")
+            } else {
+                None
+            };
+
+            NixError::Evaluation {
+                stderr: shown,
+                hint,
+            }
+        }
+    }
+}
+
+impl fmt::Display for NixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (message, stderr, hint) = match self {
+            NixError::NotFound => {
+                return write!(f, "failed to run nix, is it installed and on PATH?");
+            }
+            NixError::TooOld { version, required } => {
+                return write!(
+                    f,
+                    "{} is too old, nix print-dev-env --json needs at least nix {}.{}",
+                    version, required.0, required.1
+                );
+            }
+            NixError::FlakeNotFound { stderr, hint } => ("flake not found", stderr, *hint),
+            NixError::Evaluation { stderr, hint } => {
+                ("failed to evaluate the dev shell", stderr, *hint)
+            }
+            NixError::Build { stderr } => ("failed to build the dev shell", stderr, None),
+            NixError::UnsupportedSchema(e) => {
+                return write!(f, "unsupported nix print-dev-env json: {}", e);
+            }
+            NixError::NonUtf8Output => {
+                return write!(f, "nix print-dev-env printed invalid UTF-8");
+            }
+            NixError::Timeout { timeout } => {
+                return write!(f, "nix didn't finish within {}s", timeout.as_secs());
+            }
+            NixError::Interrupted => {
+                return write!(f, "interrupted while nix was running");
+            }
+        };
+
+        write!(f, "{}", message)?;
+        if let Some(stderr) = stderr {
+            write!(f, ":\n{}", stderr)?;
+        }
+        if let Some(hint) = hint {
+            write!(f, "\nhint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NixError {}
+
+/// Exit code for an error returned from main, 1 for anything that isn't a `NixError`.
+pub fn exit_code(error: &Error) -> i32 {
+    error
+        .downcast_ref::<NixError>()
+        .map_or(1, NixError::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_stderr() {
+        let error = NixError::from_stderr(
+            "error: path '/nix/store/abc-source/flake.nix' does not exist",
+            true,
+        );
+        assert_eq!(error.exit_code(), 5);
+        assert!(error.to_string().contains("git add"), "{}", error);
+
+        let error = NixError::from_stderr(
+            "error: builder for '/nix/store/abc-foo.drv' failed with exit code 1",
+            false,
+        );
+        assert_eq!(error.exit_code(), 7);
+        assert_eq!(error.to_string(), "failed to build the dev shell");
+
+        let error = NixError::from_stderr(
+            "error: flake 'path:/src' does not provide attribute 'devShells.x86_64-linux.ci'",
+            true,
+        );
+        assert_eq!(error.exit_code(), 6);
+        assert!(error.to_string().contains("list-shells"), "{}", error);
+
+        let error = NixError::from_stderr("error: undefined variable 'foo'", true);
+        assert_eq!(error.exit_code(), 6);
+        assert_eq!(
+            error.to_string(),
+            "failed to evaluate the dev shell:\nerror: undefined variable 'foo'"
+        );
+    }
+}
@@ -1,12 +1,13 @@
 use core::fmt;
-use std::{collections::HashMap, env, path::Path};
+use std::collections::HashMap;
 
-use anyhow::Error;
+use anyhow::{Context, Error};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     config::Config,
-    nix::{BashFunctionsType, Env, VariablesType},
+    nix::Env,
+    pattern::{Pattern, Patterns},
     shell::{combine_path, VariableValue},
 };
 
@@ -14,6 +15,18 @@ use crate::{
 pub struct FinalEnv {
     pub paths: HashMap<String, String>,
     pub variables: HashMap<String, String>,
+    pub arrays: HashMap<String, Vec<String>>,
+    pub associative_arrays: HashMap<String, HashMap<String, String>>,
+    pub bash_functions: HashMap<String, String>,
+    /// Separators of the list variables in `paths`, by variable name.
+    pub separators: HashMap<String, String>,
+}
+
+impl FinalEnv {
+    /// Separator of the list variable `name` in `paths`, `:` if it's a path variable.
+    pub fn separator(&self, name: &str) -> &str {
+        self.separators.get(name).map_or(":", String::as_str)
+    }
 }
 
 impl fmt::Display for FinalEnv {
@@ -27,35 +40,100 @@ impl fmt::Display for FinalEnv {
         for (k, v) in self.variables.iter() {
             write!(f, "\n{} = \"{}\"", k, v)?
         }
+
+        writeln!(f, "arrays: ")?;
+        for (k, v) in self.arrays.iter() {
+            write!(f, "\n{} = {:?}", k, v)?
+        }
+
+        writeln!(f, "associative arrays: ")?;
+        for (k, v) in self.associative_arrays.iter() {
+            write!(f, "\n{} = {:?}", k, v)?
+        }
+
+        writeln!(f, "functions: ")?;
+        for k in self.bash_functions.keys() {
+            write!(f, "\n{}", k)?
+        }
         Ok(())
     }
 }
 
+/// A raw filter file whose variable and function names are patterns.
+/// Variables matched by a name starting with `!` are never filtered. Otherwise a literal
+/// name takes precedence over patterns, which are tried in alphabetical order.
+struct RawFilter<'a> {
+    variables: Vec<(Pattern, &'a VariableValue)>,
+    exceptions: Vec<Pattern>,
+    functions: Patterns,
+}
+
+impl<'a> RawFilter<'a> {
+    fn new(filter: &'a Env) -> Result<RawFilter<'a>, Error> {
+        let mut names = (&filter.variables).into_iter().collect::<Vec<_>>();
+        names.sort_by_key(|(k, _)| *k);
+
+        let mut variables = Vec::new();
+        let mut exceptions = Vec::new();
+        for (k, v) in names {
+            match k.strip_prefix('!') {
+                Some(exception) => exceptions.push(Pattern::new(exception)?),
+                None => variables.push((Pattern::new(k)?, v)),
+            }
+        }
+        variables.sort_by_key(|(pattern, _)| pattern.as_literal().is_none());
+
+        Ok(RawFilter {
+            variables,
+            exceptions,
+            functions: Patterns::new((&filter.bash_functions).into_iter().map(|(k, _)| k))?,
+        })
+    }
+
+    /// The filter value for the variable `name`, None if it isn't filtered.
+    fn variable(&self, name: &str) -> Option<&'a VariableValue> {
+        if self.exceptions.iter().any(|pattern| pattern.is_match(name)) {
+            return None;
+        }
+
+        self.variables
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(name))
+            .map(|(_, value)| *value)
+    }
+}
+
+/// Whether the variable `key` is kept by a raw filter.
+/// Arrays, associative arrays and list variables only lose the elements, keys or entries
+/// in the filter, unless the filter value is empty. Anything else in the filter is removed.
 fn variable_filter(
     key: &String,
     value: &mut VariableValue,
-    filter: &VariablesType,
-    path_var_names: &[String],
+    filter: &RawFilter,
+    separators: &HashMap<String, String>,
 ) -> bool {
-    if let Some(f_value) = filter.get(key) {
+    if let Some(f_value) = filter.variable(key) {
         use VariableValue::*;
         match (f_value, value) {
-            (Array { value: _ }, Array { value: _ })
-            | (Associative { value: _ }, Associative { value: _ }) => {
-                return false;
+            (Array { value: f_value }, Array { value }) if !f_value.is_empty() => {
+                value.retain(|element| !f_value.contains(element));
+                return true;
             }
-            (Var { value: f_value }, Var { value })
-            | (Exported { value: f_value }, Exported { value })
-                if path_var_names.contains(key) =>
-            {
-                let f_paths = env::split_paths(&f_value).collect::<Vec<_>>();
-                let paths = env::split_paths(&value).filter(|i| !f_paths.contains(i));
-                let joined_paths = env::join_paths(paths);
-                if let Ok(paths) = joined_paths {
-                    if let Ok(paths) = paths.into_string() {
-                        *value = paths;
-                    }
-                }
+            (Associative { value: f_value }, Associative { value }) if !f_value.is_empty() => {
+                value.retain(|k, _| !f_value.contains_key(k));
+                return true;
+            }
+            (
+                Var { value: f_value } | Exported { value: f_value },
+                Var { value } | Exported { value },
+            ) if !f_value.is_empty() && separators.contains_key(key) => {
+                let separator = &separators[key];
+                let f_entries = f_value.split(separator.as_str()).collect::<Vec<_>>();
+                *value = value
+                    .split(separator.as_str())
+                    .filter(|entry| !f_entries.contains(entry))
+                    .collect::<Vec<_>>()
+                    .join(separator);
                 return true;
             }
             _ => return false,
@@ -74,12 +152,102 @@ fn variable_filter_empty(_: &String, value: &mut VariableValue) -> bool {
     }
 }
 
-fn function_filter(key: &String, _: &mut String, filter: &BashFunctionsType) -> bool {
-    !filter.contains(key)
+fn function_filter(key: &str, _: &mut String, filter: &Patterns) -> bool {
+    !filter.is_match(key)
+}
+
+/// Well-known search path variables, always treated as path_vars.
+const KNOWN_PATH_VARS: &[&str] = &[
+    "PATH",
+    "XDG_DATA_DIRS",
+    "XDG_CONFIG_DIRS",
+    "PKG_CONFIG_PATH",
+    "CMAKE_PREFIX_PATH",
+    "CMAKE_INCLUDE_PATH",
+    "CMAKE_LIBRARY_PATH",
+    "ACLOCAL_PATH",
+    "GI_TYPELIB_PATH",
+    "GIO_EXTRA_MODULES",
+    "GST_PLUGIN_SYSTEM_PATH_1_0",
+    "QT_PLUGIN_PATH",
+    "PYTHONPATH",
+    "PERL5LIB",
+    "NODE_PATH",
+    "LD_LIBRARY_PATH",
+    "LIBRARY_PATH",
+    "CPATH",
+    "C_INCLUDE_PATH",
+    "CPLUS_INCLUDE_PATH",
+    "MANPATH",
+    "INFOPATH",
+    "TERMINFO_DIRS",
+];
+
+/// Whether `value` looks like a list of absolute paths.
+/// A single path only counts if the name says it's a search path, so `out` or `HOME`
+/// aren't prepended to the inherited value.
+fn is_path_like(name: &str, value: &str) -> bool {
+    let mut entries = value
+        .split(':')
+        .filter(|entry| !entry.is_empty())
+        .peekable();
+    if entries.peek().is_none() || !entries.all(|entry| entry.starts_with('/')) {
+        return false;
+    }
+
+    value.contains(':') || name.ends_with("PATH") || name.ends_with("DIRS")
 }
 
-fn function_filter_empty(_: &String, value: &mut String) -> bool {
-    !value.is_empty()
+/// Variables whose values are lists, by their separator.
+/// Path variables are `:` separated: the built-in ones, the detected ones if a config sets
+/// detect_path_vars, and path_vars, minus not_path_vars.
+/// Other lists like flags declare their separator in the config.
+pub fn list_separators(
+    envs: &[&Env],
+    config_file: Option<&Config>,
+    config_str: Option<&Config>,
+) -> HashMap<String, String> {
+    let configs: Vec<&Config> = config_file.into_iter().chain(config_str).collect();
+    let mut separators: HashMap<String, String> = HashMap::new();
+    for name in KNOWN_PATH_VARS {
+        separators.insert(name.to_string(), ":".to_string());
+    }
+
+    if configs.iter().any(|config| config.detect_path_vars) {
+        let mut detected: HashMap<&str, bool> = HashMap::new();
+        for env in envs {
+            for (k, v) in &env.variables {
+                let path_like = match v {
+                    VariableValue::Exported { value } | VariableValue::Var { value } => {
+                        is_path_like(k, value)
+                    }
+                    _ => false,
+                };
+                *detected.entry(k).or_insert(true) &= path_like;
+            }
+        }
+
+        for (name, _) in detected.into_iter().filter(|(_, path_like)| *path_like) {
+            separators.insert(name.to_string(), ":".to_string());
+        }
+    }
+
+    for config in &configs {
+        separators.retain(|name, _| !config.not_path_vars.is_match(name));
+    }
+
+    for config in configs {
+        let names = envs.iter().flat_map(|env| &env.variables).map(|(k, _)| k);
+        for name in names.filter(|name| config.path_vars.is_match(name)) {
+            separators.insert(name.to_string(), ":".to_string());
+        }
+        for name in config.path_vars.literals() {
+            separators.insert(name.to_string(), ":".to_string());
+        }
+        separators.extend(config.separators.clone());
+    }
+
+    separators
 }
 
 pub fn filter(
@@ -92,67 +260,58 @@ pub fn filter(
     let mut res: FinalEnv = FinalEnv {
         paths: HashMap::new(),
         variables: HashMap::new(),
+        arrays: HashMap::new(),
+        associative_arrays: HashMap::new(),
+        bash_functions: HashMap::new(),
+        separators: HashMap::new(),
     };
 
-    let mut path_var_names: Vec<String> = vec!["PATH".to_string(), "XDG_DATA_DIRS".to_string()];
+    let separators = list_separators(&[&env], config_file.as_ref(), config_str.as_ref());
 
-    if let Some(config) = &config_file {
-        path_var_names.extend_from_slice(config.path_vars.as_slice());
-    }
+    let env = filter_raw(env, filter_file, filter_str, &separators)?;
 
-    if let Some(config) = &config_str {
-        path_var_names.extend_from_slice(config.path_vars.as_slice());
+    for (k, v) in &env.bash_functions {
+        res.bash_functions.insert(k.to_string(), v.to_string());
     }
 
-    let env = filter_raw(env, filter_file, filter_str, &path_var_names)?;
-
     if config_file.is_none() && config_str.is_none() {
-        let config: Config = Config {
-            path_vars: Vec::new(),
-            paths: HashMap::new(),
-            variables: Vec::new(),
-        };
-        filter_config(&env, config, &path_var_names, &mut res);
+        filter_config(&env, Config::default(), &separators, &mut res);
     } else {
         if let Some(config) = config_file {
-            filter_config(&env, config, &path_var_names, &mut res);
+            filter_config(&env, config, &separators, &mut res);
         }
 
         if let Some(config) = config_str {
-            filter_config(&env, config, &path_var_names, &mut res);
+            filter_config(&env, config, &separators, &mut res);
         }
     }
 
-    Ok(res)
-}
+    res.separators = separators;
 
-fn filter_path(p: &Path, paths: &[String]) -> Option<String> {
-    if let Some(p) = p.as_os_str().to_str() {
-        if paths.contains(&p.to_string()) {
-            None
-        } else {
-            Some(p.to_string())
-        }
-    } else {
-        None
-    }
+    Ok(res)
 }
 
-fn filter_config(env: &Env, config: Config, path_var_names: &[String], out_env: &mut FinalEnv) {
+fn filter_config(
+    env: &Env,
+    config: Config,
+    separators: &HashMap<String, String>,
+    out_env: &mut FinalEnv,
+) {
     for (k, v) in &env.variables {
-        if config.variables.contains(k) {
+        if config.variables.is_match(k) {
             continue;
         }
 
         match v {
             VariableValue::Exported { value } | VariableValue::Var { value } => {
-                if path_var_names.contains(k) {
-                    if config.paths.contains_key(k) {
+                if let Some(separator) = separators.get(k) {
+                    if let Some(removed) = config.paths.get(k) {
                         let mut paths = String::new();
-                        for s in env::split_paths(&value).filter_map(|x| {
-                            filter_path(&x, config.paths.get(k).expect("checked earlier"))
-                        }) {
-                            paths = combine_path(paths, s.as_str(), ":");
+                        for s in value
+                            .split(separator.as_str())
+                            .filter(|entry| !entry.is_empty() && !removed.is_match(entry))
+                        {
+                            paths = combine_path(paths, s, separator);
                         }
 
                         out_env.paths.insert(k.to_string(), paths);
@@ -163,7 +322,14 @@ fn filter_config(env: &Env, config: Config, path_var_names: &[String], out_env:
                     out_env.variables.insert(k.to_string(), value.to_string());
                 }
             }
-            _ => {}
+            VariableValue::Array { value } => {
+                out_env.arrays.insert(k.to_string(), value.clone());
+            }
+            VariableValue::Associative { value } => {
+                out_env
+                    .associative_arrays
+                    .insert(k.to_string(), value.clone());
+            }
         }
     }
 }
@@ -172,24 +338,18 @@ fn filter_raw(
     mut env: Env,
     filter_file: Option<Env>,
     filter_str: Option<Env>,
-    path_var_names: &[String],
+    separators: &HashMap<String, String>,
 ) -> Result<Env, Error> {
-    if let Some(filter) = filter_file {
-        env.variables
-            .retain(|k, v| variable_filter(k, v, &filter.variables, path_var_names));
-        env.bash_functions
-            .retain(|k, v| function_filter(k, v, &filter.bash_functions));
-    }
-
-    if let Some(filter) = filter_str {
+    for filter in filter_file.iter().chain(&filter_str) {
+        let filter = RawFilter::new(filter).context("invalid pattern in filter")?;
         env.variables
-            .retain(|k, v| variable_filter(k, v, &filter.variables, path_var_names));
+            .retain(|k, v| variable_filter(k, v, &filter, separators));
         env.bash_functions
-            .retain(|k, v| function_filter(k, v, &filter.bash_functions));
+            .retain(|k, v| function_filter(k, v, &filter.functions));
     }
 
     env.variables.retain(variable_filter_empty);
-    env.bash_functions.retain(function_filter_empty);
+    env.bash_functions.retain(|_, value| !value.is_empty());
 
     Ok(env)
 }
@@ -200,6 +360,13 @@ mod tests {
 
     use super::*;
 
+    fn filter_env(variables: HashMap<String, VariableValue>) -> Env {
+        Env {
+            bash_functions: HashMap::new().into(),
+            variables: variables.into(),
+        }
+    }
+
     #[test]
     fn test_read_raw_filter_str() {
         let filter_str = r#"
@@ -213,7 +380,7 @@ mod tests {
                 }
             }
         "#;
-        let result: Result<Env, _> = serde_json::from_str(&filter_str);
+        let result: Result<Env, _> = serde_json::from_str(filter_str);
 
         assert!(
             result.is_ok(),
@@ -236,7 +403,7 @@ mod tests {
                 "variables": [ "var1", "var2" ]
             }
         "#;
-        let result: Result<Config, _> = serde_json::from_str(&config_str);
+        let result: Result<Config, _> = serde_json::from_str(config_str);
 
         assert!(
             result.is_ok(),
@@ -281,7 +448,7 @@ mod tests {
             }
         "#;
 
-        let env: Result<Env, _> = serde_json::from_str(&env_str);
+        let env: Result<Env, _> = serde_json::from_str(env_str);
 
         assert!(
             env.is_ok(),
@@ -292,13 +459,13 @@ mod tests {
             env_str
         );
 
-        let empty_vec = Vec::new();
+        let no_separators = HashMap::new();
         let filter = Some(
-            serde_json::from_str(&filter_str.to_string())
+            serde_json::from_str(filter_str)
                 .context("failed to deserialize filter json str")
                 .unwrap(),
         );
-        let env = filter_raw(env.unwrap(), None, filter, &empty_vec);
+        let env = filter_raw(env.unwrap(), None, filter, &no_separators);
 
         assert!(env.is_ok(), "filter_env failed: {:#}", env.unwrap_err());
 
@@ -352,6 +519,14 @@ mod tests {
                 }
             }
         }
+
+        for k in ["var1", "var3", "var5"] {
+            assert!(
+                env.variables.get(k).is_some(),
+                "{} should have been kept",
+                k
+            );
+        }
     }
 
     #[test]
@@ -378,7 +553,7 @@ mod tests {
             }
         "#;
 
-        let env: Result<Env, _> = serde_json::from_str(&env_str);
+        let env: Result<Env, _> = serde_json::from_str(env_str);
 
         assert!(
             env.is_ok(),
@@ -389,19 +564,26 @@ mod tests {
             env_str
         );
 
-        let config: Config = serde_json::from_str(&config_str.to_string())
+        let config: Config = serde_json::from_str(config_str)
             .context("failed to deserialize config json str")
             .unwrap();
 
         let mut final_env: FinalEnv = FinalEnv {
             paths: HashMap::new(),
             variables: HashMap::new(),
+            arrays: HashMap::new(),
+            associative_arrays: HashMap::new(),
+            bash_functions: HashMap::new(),
+            separators: HashMap::new(),
         };
 
-        let mut path_var_names = Vec::new();
-        path_var_names.extend_from_slice(config.path_vars.as_slice());
+        let separators = config
+            .path_vars
+            .literals()
+            .map(|name| (name.to_string(), ":".to_string()))
+            .collect();
 
-        filter_config(&env.unwrap(), config, &path_var_names, &mut final_env);
+        filter_config(&env.unwrap(), config, &separators, &mut final_env);
 
         assert!(!final_env.paths.is_empty(), "final_env paths is empty");
         assert!(
@@ -425,4 +607,212 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_is_path_like() {
+        assert!(is_path_like(
+            "GI_TYPELIB_PATH",
+            "/nix/store/a/lib:/nix/store/b/lib"
+        ));
+        assert!(is_path_like("MY_PATH", "/nix/store/a/share"));
+        assert!(is_path_like("var", "/a::/b:"));
+        assert!(!is_path_like("out", "/nix/store/a"));
+        assert!(!is_path_like("NIX_PATH", "nixpkgs=/nix/store/a:/b"));
+        assert!(!is_path_like("var", ":"));
+        assert!(!is_path_like("var", ""));
+    }
+
+    #[test]
+    fn test_list_separators() {
+        let env: Env = serde_json::from_str(
+            r#"
+            {
+                "bashFunctions": { },
+                "variables": {
+                    "GI_TYPELIB_PATH": { "type": "exported", "value": "/a/lib:/b/lib" },
+                    "EXTRA_DIRS": { "type": "exported", "value": "/a:/b" },
+                    "out": { "type": "exported", "value": "/nix/store/a" },
+                    "var": { "type": "var", "value": "value" }
+                }
+            }
+            "#,
+        )
+        .unwrap();
+
+        let separators = list_separators(&[&env], None, None);
+        assert!(separators.contains_key("PATH"));
+        assert!(separators.contains_key("GI_TYPELIB_PATH"));
+        assert!(!separators.contains_key("EXTRA_DIRS"));
+
+        let config: Config = serde_json::from_str(
+            r#"
+            {
+                "path_vars": [ "var" ],
+                "detect_path_vars": true,
+                "not_path_vars": [ "GI_TYPELIB_PATH", "var" ],
+                "paths": { },
+                "variables": [ ]
+            }
+            "#,
+        )
+        .unwrap();
+
+        let separators = list_separators(&[&env], None, Some(&config));
+        assert!(separators.contains_key("EXTRA_DIRS"));
+        assert!(!separators.contains_key("GI_TYPELIB_PATH"));
+        assert!(!separators.contains_key("out"));
+        assert_eq!(
+            separators["var"], ":",
+            "path_vars take precedence over not_path_vars"
+        );
+    }
+
+    #[test]
+    fn test_variable_filter() {
+        use VariableValue::*;
+
+        let separators = HashMap::from([("list".to_string(), ":".to_string())]);
+        let exported = |value: &str| Exported {
+            value: value.to_string(),
+        };
+        let var = |value: &str| Var {
+            value: value.to_string(),
+        };
+        let array = |value: &[&str]| Array {
+            value: value.iter().map(|v| v.to_string()).collect(),
+        };
+        let associative = |value: &[(&str, &str)]| Associative {
+            value: value
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        let scalars = || [("var", var("a:b")), ("list", exported("a:b"))];
+
+        // (variable, value, filter value, value after filtering or None if removed)
+        let mut cases = vec![
+            ("list", var("a:b:c"), var("b"), Some(var("a:c"))),
+            ("list", var("a:b:c"), exported("a:c"), Some(var("b"))),
+            ("list", exported("a:b:c"), var("c"), Some(exported("a:b"))),
+            (
+                "list",
+                exported("a:b:c"),
+                exported("d"),
+                Some(exported("a:b:c")),
+            ),
+            ("list", exported("a:b"), exported(""), None),
+            ("var", var("b"), var("b"), None),
+            ("var", exported("a:b"), exported("a"), None),
+            (
+                "arr",
+                array(&["1", "2", "3"]),
+                array(&["2", "4"]),
+                Some(array(&["1", "3"])),
+            ),
+            ("arr", array(&["1", "2"]), array(&[]), None),
+            (
+                "assoc",
+                associative(&[("1", "v1"), ("2", "v2")]),
+                associative(&[("2", ""), ("3", "v3")]),
+                Some(associative(&[("1", "v1")])),
+            ),
+            ("assoc", associative(&[("1", "v1")]), associative(&[]), None),
+            ("arr", array(&["1"]), associative(&[("0", "1")]), None),
+            ("assoc", associative(&[("1", "v1")]), array(&["1"]), None),
+        ];
+        for (key, value) in scalars() {
+            cases.push((key, value, array(&["a"]), None));
+        }
+        for (key, value) in scalars() {
+            cases.push((key, value, associative(&[("a", "")]), None));
+        }
+        cases.push(("arr", array(&["a"]), var("a"), None));
+        cases.push(("arr", array(&["a"]), exported("a"), None));
+        cases.push(("assoc", associative(&[("a", "")]), var("a"), None));
+        cases.push(("assoc", associative(&[("a", "")]), exported("a"), None));
+
+        for (key, mut value, f_value, expected) in cases {
+            let message = format!("{} = {:?} filtered by {:?}", key, value, f_value);
+            let filter = filter_env(HashMap::from([(key.to_string(), f_value)]));
+            let filter = RawFilter::new(&filter).unwrap();
+
+            let kept = variable_filter(&key.to_string(), &mut value, &filter, &separators);
+
+            assert_eq!(kept.then_some(value), expected, "{}", message);
+        }
+
+        let mut value = var("value");
+        let filter = filter_env(HashMap::from([("var".to_string(), var("value"))]));
+        assert!(variable_filter(
+            &"other".to_string(),
+            &mut value,
+            &RawFilter::new(&filter).unwrap(),
+            &separators
+        ));
+        assert_eq!(value, var("value"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        let env_str = r#"
+            {
+                "bashFunctions": { "_nix_hook": "b", "_nix_other": "b", "genericBuild": "b" },
+                "variables": {
+                    "NIX_CC": { "type": "exported", "value": "/nix/store/cc" },
+                    "NIX_LDFLAGS": { "type": "exported", "value": "-L/lib" },
+                    "NIX_STORE": { "type": "exported", "value": "/nix/store" },
+                    "PATH": { "type": "exported", "value": "/nix/store/a-gcc-wrapper-13/bin:/nix/store/b/bin" },
+                    "out": { "type": "exported", "value": "/nix/store/out" },
+                    "outputDev": { "type": "exported", "value": "out" },
+                    "src": { "type": "exported", "value": "/src" }
+                }
+            }
+        "#;
+        let filter_str = r#"
+            {
+                "bashFunctions": { "glob:_nix_*": "", "!_nix_hook": "" },
+                "variables": {
+                    "re:o.*": { "type": "var", "value": "" },
+                    "!out": { "type": "var", "value": "" }
+                }
+            }
+        "#;
+        let config_str = r#"
+            {
+                "path_vars": [],
+                "paths": { "PATH": ["glob:/nix/store/*-gcc-wrapper-*/bin"] },
+                "variables": ["glob:NIX_*", "!NIX_CC", "re:s.c"]
+            }
+        "#;
+
+        let env: Env = serde_json::from_str(env_str).unwrap();
+        let filter: Env = serde_json::from_str(filter_str).unwrap();
+        let config: Config = serde_json::from_str(config_str).unwrap();
+
+        let separators = list_separators(&[&env], None, Some(&config));
+        let env = filter_raw(env, None, Some(filter), &separators).unwrap();
+        assert!(env.bash_functions.contains(&"_nix_hook".to_string()));
+        assert!(env.bash_functions.contains(&"genericBuild".to_string()));
+        assert!(!env.bash_functions.contains(&"_nix_other".to_string()));
+        assert!(!env.variables.contains(&"outputDev".to_string()));
+        assert!(
+            env.variables.contains(&"out".to_string()),
+            "exceptions take precedence"
+        );
+
+        let mut final_env = FinalEnv {
+            paths: HashMap::new(),
+            variables: HashMap::new(),
+            arrays: HashMap::new(),
+            associative_arrays: HashMap::new(),
+            bash_functions: HashMap::new(),
+            separators: HashMap::new(),
+        };
+        filter_config(&env, config, &separators, &mut final_env);
+
+        assert_eq!(final_env.paths["PATH"], "/nix/store/b/bin");
+        let mut variables = final_env.variables.keys().collect::<Vec<_>>();
+        variables.sort();
+        assert_eq!(variables, ["NIX_CC", "out"]);
+    }
 }
@@ -0,0 +1,198 @@
+use crate::{cache::cache_dir, nix::Env, shell::VariableValue};
+use anyhow::{anyhow, Context, Error, Result};
+use clap::ValueEnum;
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// What happens to the gc root of a dev shell when the shell exits.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GcRootPolicy {
+    /// Keep the gc root until the next time the shell is entered.
+    #[default]
+    Keep,
+    /// Remove the gc root when the shell exits.
+    Remove,
+    /// Don't register a gc root.
+    None,
+}
+
+/// Indirect gc roots for the store paths used by a dev shell,
+/// similar to the profile created by `nix develop --profile`.
+/// Every process registers its roots in its own `<dir>/<pid>`, so shells of
+/// the same project don't remove each other's roots.
+pub struct GcRoot {
+    dir: PathBuf,
+}
+
+impl GcRoot {
+    /// Registers `dir/<pid>/root`, `dir/<pid>/root-2`, ... as indirect gc roots for every
+    /// store path referenced by the env. Once they exist, the roots left in `dir` by
+    /// processes that exited are removed, the ones of running shells are kept.
+    pub fn register(dir: &Path, env: &Env) -> Result<GcRoot, Error> {
+        let pid = std::process::id();
+        let root = GcRoot {
+            dir: dir.join(pid.to_string()),
+        };
+
+        // left by an exited process with the same pid
+        if root.dir.exists() {
+            fs::remove_dir_all(&root.dir).with_context(|| {
+                format!("failed to remove old gc roots: {}", root.dir.display())
+            })?;
+        }
+        fs::create_dir_all(&root.dir)
+            .with_context(|| format!("failed to create gc root dir: {}", root.dir.display()))?;
+
+        let paths = store_paths(env);
+        if !paths.is_empty() {
+            let output = Command::new("nix-store")
+                .arg("--add-root")
+                .arg(root.dir.join("root"))
+                .arg("--realise")
+                .args(&paths)
+                .output()
+                .context("nix-store --add-root failed.")?;
+
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(anyhow!("failed to register gc root: {}", stderr));
+            }
+        }
+
+        remove_stale(dir, pid)?;
+
+        Ok(root)
+    }
+
+    pub fn remove(self) -> Result<(), Error> {
+        fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("failed to remove gc roots: {}", self.dir.display()))?;
+        // fails if other shells still have their roots in there
+        if let Some(parent) = self.dir.parent() {
+            let _ = fs::remove_dir(parent);
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes everything in `dir` except the roots of `pid` and of other running processes.
+fn remove_stale(dir: &Path, pid: u32) -> Result<(), Error> {
+    let entries =
+        fs::read_dir(dir).with_context(|| format!("failed to read gc roots: {}", dir.display()))?;
+
+    for entry in entries {
+        let entry = entry?;
+        let running = match entry.file_name().to_str().map(str::parse::<libc::pid_t>) {
+            Some(Ok(other)) => other as u32 == pid || is_running(other),
+            _ => false,
+        };
+        if running {
+            continue;
+        }
+
+        let path = entry.path();
+        let removed = match entry.file_type()?.is_dir() {
+            true => fs::remove_dir_all(&path),
+            false => fs::remove_file(&path),
+        };
+        removed.with_context(|| format!("failed to remove old gc roots: {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+fn is_running(pid: libc::pid_t) -> bool {
+    // SAFETY: kill with signal 0 only checks whether the process exists
+    let res = unsafe { libc::kill(pid, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// `$XDG_CACHE_HOME/nix-dev-env/gcroots/<key>`
+pub fn gc_root_dir(key: &str) -> Result<PathBuf, Error> {
+    Ok(cache_dir()?.join("gcroots").join(key))
+}
+
+/// Collects the top level store paths referenced by the variables of the env,
+/// including `out`. Only paths that exist locally are returned.
+pub fn store_paths(env: &Env) -> Vec<PathBuf> {
+    let store_dir = match env.variables.get("NIX_STORE") {
+        Some(VariableValue::Exported { value } | VariableValue::Var { value }) => value.as_str(),
+        _ => "/nix/store",
+    };
+
+    let mut paths = BTreeSet::new();
+
+    for (_, v) in &env.variables {
+        match v {
+            VariableValue::Exported { value } | VariableValue::Var { value } => {
+                paths.extend(find_store_paths(value, store_dir));
+            }
+            VariableValue::Array { value } => {
+                for value in value {
+                    paths.extend(find_store_paths(value, store_dir));
+                }
+            }
+            VariableValue::Associative { value } => {
+                for value in value.values() {
+                    paths.extend(find_store_paths(value, store_dir));
+                }
+            }
+        }
+    }
+
+    paths.into_iter().filter(|p| p.exists()).collect()
+}
+
+/// Finds every `<store_dir>/<hash>-<name>` in a string.
+fn find_store_paths(value: &str, store_dir: &str) -> Vec<PathBuf> {
+    const HASH_LEN: usize = 32;
+
+    let prefix = format!("{}/", store_dir.trim_end_matches('/'));
+    let is_name_char =
+        |c: char| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.' | '_' | '?' | '=');
+
+    let mut paths = Vec::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find(&prefix) {
+        rest = &rest[start + prefix.len()..];
+
+        let len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+        let name = &rest[..len];
+
+        if name.len() > HASH_LEN + 1 && name.as_bytes()[HASH_LEN] == b'-' {
+            paths.push(Path::new(&prefix).join(name));
+        }
+
+        rest = &rest[len..];
+    }
+
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_store_paths() {
+        let value = "/nix/store/9rn0jd0pd5rhyhxr3ldq8w2mgqjb4pfr-bash-5.2p32/bin:\
+            /usr/bin:/nix/store/invalid:\
+            -I/nix/store/0a5ybwqb5rn2l3m6ng8rkzs6j3dsb2qi-zlib-1.3.1-dev/include";
+
+        assert_eq!(
+            find_store_paths(value, "/nix/store"),
+            [
+                PathBuf::from("/nix/store/9rn0jd0pd5rhyhxr3ldq8w2mgqjb4pfr-bash-5.2p32"),
+                PathBuf::from("/nix/store/0a5ybwqb5rn2l3m6ng8rkzs6j3dsb2qi-zlib-1.3.1-dev"),
+            ]
+        );
+
+        assert!(find_store_paths(value, "/other/store").is_empty());
+    }
+}
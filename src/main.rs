@@ -1,45 +1,194 @@
 use anyhow::{anyhow, Context, Error, Result};
-use clap::Parser;
+use cache::Cache;
+use clap::{Parser, Subcommand};
 use config::Config;
-use nix::Env;
-use shell::start_shell;
+use error::NixError;
+use gcroot::{GcRoot, GcRootPolicy};
+use merge::ConflictResolution;
+use nix::{Env, Installable, NixLog, NixSource};
+use profile::Profile;
+use shell::{shell_command, start_shell, ShellOptions};
+use source::{EnvSource, FileSource};
 use std::{
     fs::{self, File},
     io::BufReader,
+    os::unix::process::CommandExt,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
+mod cache;
 mod config;
+mod error;
 mod filter;
+mod gcroot;
+mod merge;
 mod nix;
+mod pattern;
+mod profile;
 mod shell;
+mod source;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-/// Note: functions and arrays/assiocitive arrays are currently not implemented.
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    after_help = "Exit codes:
+  1  other errors
+  2  invalid arguments
+  3  nix not found
+  4  nix too old
+  5  flake not found
+  6  nix evaluation failed
+  7  building the dev shell failed
+  8  unsupported nix print-dev-env json
+  9  nix printed invalid UTF-8
+  10 nix timed out
+  130 interrupted"
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Path to the dev shell.
-    path: Option<String>,
+    /// Either a flake reference or a non-flake directory or .nix file,
+    /// directories without a flake.nix use their shell.nix or default.nix.
+    /// An attribute of a .nix file can be selected with file.nix#attr.
+    /// If this isn't specified, the nearest flake.nix or shell.nix in the current
+    /// directory or its parents is used, up to the root of the git repository.
+    /// Multiple dev shells are merged, path variables are concatenated in the given order.
+    #[arg(verbatim_doc_comment)]
+    path: Vec<String>,
+
+    /// Run this command in the dev env instead of starting a shell, e.g. `-- cargo build`.
+    /// Its exit status is returned, SIGTERM and SIGHUP are forwarded to it.
+    /// The shellHook isn't run and arrays aren't set.
+    #[arg(
+        last = true,
+        value_name = "COMMAND",
+        conflicts_with = "print",
+        verbatim_doc_comment
+    )]
+    exec: Vec<String>,
+
+    /// Which value to use when multiple dev shells set a variable or function differently.
+    /// Overrides on_conflict from the config, defaults to first.
+    #[arg(long, value_enum, verbatim_doc_comment)]
+    on_conflict: Option<ConflictResolution>,
+
+    /// Don't search parent directories if path isn't specified,
+    /// let nix use the current directory.
+    #[arg(long, default_value_t = false, verbatim_doc_comment)]
+    no_discover: bool,
+
+    /// Name of the devShell of the flake to use, e.g. ci for devShells.<system>.ci.
+    #[arg(short, long, verbatim_doc_comment)]
+    dev_shell: Option<String>,
+
+    /// Choose the devShell of the flake from a numbered list.
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with = "dev_shell",
+        verbatim_doc_comment
+    )]
+    select_shell: bool,
+
+    /// Extra argument passed verbatim to nix print-dev-env and list-shells,
+    /// can be given multiple times and is appended to nix_args from the config.
+    /// e.g. --nix-arg --override-input --nix-arg nixpkgs --nix-arg path:/src/nixpkgs
+    #[arg(
+        long,
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        global = true,
+        verbatim_doc_comment
+    )]
+    nix_arg: Vec<String>,
+
+    /// Pass a nix expression as argument to a non-flake shell.nix/default.nix.
+    #[arg(long, num_args = 2, value_names = ["NAME", "EXPR"], global = true, verbatim_doc_comment)]
+    arg: Vec<String>,
+
+    /// Pass a string as argument to a non-flake shell.nix/default.nix.
+    #[arg(long, num_args = 2, value_names = ["NAME", "STRING"], global = true, verbatim_doc_comment)]
+    argstr: Vec<String>,
+
+    /// Don't show the output of nix while it evaluates the dev shell.
+    #[arg(
+        short,
+        long,
+        default_value_t = false,
+        global = true,
+        verbatim_doc_comment
+    )]
+    quiet: bool,
+
+    /// Write the output of nix to this file.
+    #[arg(long, global = true, verbatim_doc_comment)]
+    nix_log: Option<PathBuf>,
 
-    /// Which shell to start.
+    /// Kill nix if evaluating the dev shell takes longer than this many seconds.
+    /// The last cached env or the --profile is used instead, if there is one.
+    #[arg(long, value_name = "SECONDS", global = true, verbatim_doc_comment)]
+    timeout: Option<u64>,
+
+    /// Which shell to start: bash, zsh, ksh, fish or nu.
     /// If this isn't specified, use SHELL from env.
+    /// The dev env is set up again after the startup files of the shell, e.g. ~/.bashrc.
+    /// Shells other than bash get the functions of the dev shell as wrappers that run them
+    /// in bash, fish and nu also run the shellHook in bash.
+    /// fish has no associative arrays, they are skipped.
     #[arg(short, long, verbatim_doc_comment)]
     shell: Option<String>,
 
+    /// Start from an empty environment that only keeps HOME, USER, TERM, DISPLAY
+    /// and the variables in keep of the config, like nix develop --ignore-environment.
+    /// The startup files of the shell, e.g. ~/.bashrc, aren't run.
+    #[arg(
+        long,
+        visible_alias = "ignore-environment",
+        default_value_t = false,
+        verbatim_doc_comment
+    )]
+    pure: bool,
+
+    /// Put this in front of the prompt of the shell.
+    #[arg(long, value_name = "PREFIX", verbatim_doc_comment)]
+    prompt: Option<String>,
+
+    /// Export the functions of the dev shell as BASH_FUNC_name%%,
+    /// so every bash started from the shell has them.
+    #[arg(long, default_value_t = false, verbatim_doc_comment)]
+    export_functions: bool,
+
+    /// Don't run the shellHook of the dev shell.
+    /// shell_hooks in the config allows or denies it per project.
+    #[arg(long, default_value_t = false, verbatim_doc_comment)]
+    no_shell_hook: bool,
+
     /// path to the json config file.
     /// config_file and config_str will be merged.
-    #[arg(short, long, verbatim_doc_comment)]
+    /// Names in path_vars, not_path_vars and variables and the entries in paths
+    /// match literally, or as a glob with glob:NIX_* or a whole-string regex with re:NIX_.*
+    /// An entry starting with ! is an exception and wins over the other entries,
+    /// e.g. ["glob:NIX_*", "!NIX_CC"] drops every NIX_ variable except NIX_CC.
+    #[arg(short, long, global = true, verbatim_doc_comment)]
     config_file: Option<PathBuf>,
 
     /// config string in json format.
     /// config_file and config_str will be merged.
-    #[arg(long, verbatim_doc_comment)]
+    #[arg(long, global = true, verbatim_doc_comment)]
     config_str: Option<String>,
 
     /// path to json file of things to filter out.
     /// needs to be in the same format as nix print-dev-env --json.
     /// arrays/associative arrays and vars handled as paths will filter out
-    /// only the things supplied if value isn't empty.
+    /// only the things supplied if value isn't empty, an empty value removes the variable.
+    /// Names can be patterns like in the config, variables matched by a literal name
+    /// use its value, otherwise the first matching pattern in alphabetical order.
     /// filter_file_raw and filter_file_str will be merged.
     #[arg(long, verbatim_doc_comment)]
     filter_file_raw: Option<PathBuf>,
@@ -47,21 +196,134 @@ struct Cli {
     /// string in json format of things to filter out.
     /// needs to be in the same format as nix print-dev-env --json.
     /// arrays/associative arrays and vars handled as paths will filter out
-    /// only the things supplied if value isn't empty.
+    /// only the things supplied if value isn't empty, an empty value removes the variable.
+    /// Names can be patterns like in the config, see --filter-file-raw.
     /// filter_file_raw and filter_file_str will be merged.
     #[arg(long, verbatim_doc_comment)]
     filter_str_raw: Option<String>,
 
+    /// Re-evaluate the dev shell even if a cached env exists.
+    #[arg(short, long, default_value_t = false, verbatim_doc_comment)]
+    refresh: bool,
+
+    /// Extra files that invalidate the cached env when they change.
+    /// flake.nix and flake.lock are always watched.
+    #[arg(short, long, verbatim_doc_comment)]
+    watch: Vec<PathBuf>,
+
+    /// Read the env from a file in the nix print-dev-env --json format
+    /// instead of evaluating nix. Use - to read from stdin,
+    /// together with --print or -- COMMAND.
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = ["refresh", "watch", "from_profile"],
+        verbatim_doc_comment
+    )]
+    env_file: Option<PathBuf>,
+
+    /// Save the evaluated env into this profile, with a gc root.
+    /// Names are stored in XDG_DATA_HOME/nix-dev-env/profiles,
+    /// anything containing a / is used as a path.
+    #[arg(long, verbatim_doc_comment)]
+    profile: Option<String>,
+
+    /// Enter the env saved in this profile without evaluating nix.
+    #[arg(long, conflicts_with_all = ["refresh", "watch"], verbatim_doc_comment)]
+    from_profile: Option<String>,
+
+    /// What to do with the gc root of the dev shell when the shell exits.
+    /// keep: leave it until the shell is entered again.
+    /// remove: delete it when the shell exits.
+    /// none: don't register a gc root.
+    #[arg(long, value_enum, default_value_t, verbatim_doc_comment)]
+    gc_root: GcRootPolicy,
+
     /// Print final env, but don't start shell.
     #[arg(short, long, default_value_t = false, verbatim_doc_comment)]
     print: bool,
 }
 
-fn main() -> Result<(), Error> {
-    let args = Cli::parse();
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// List the devShells of a flake for the current system.
+    ListShells {
+        /// Path to the flake.
+        /// If this isn't specified, the flake is searched like for the dev shell.
+        #[arg(verbatim_doc_comment)]
+        path: Option<String>,
+    },
+}
+
+/// Searches the current directory and its parents for the dev shell.
+fn discover_path() -> Result<Option<String>, Error> {
+    let cwd = std::env::current_dir().context("failed to get current directory")?;
+
+    Ok(nix::discover(&cwd).map(|file| {
+        eprintln!("using {}", file.display());
+        // a shell.nix is evaluated as a file, a flake.nix by its directory
+        if file.ends_with("flake.nix") {
+            file.parent().unwrap_or(&file).display().to_string()
+        } else {
+            file.display().to_string()
+        }
+    }))
+}
+
+/// The flake reference of a path without its attribute, e.g. `.` for `.#ci`.
+fn flake_ref(path: Option<&str>) -> Result<String, Error> {
+    let flake = match Installable::detect(path) {
+        Installable::Flake(flake) => flake.unwrap_or_else(|| ".".to_string()),
+        Installable::File { file, .. } => {
+            return Err(anyhow!(
+                "{} isn't a flake, devShells can only be selected from flakes",
+                file.display()
+            ));
+        }
+    };
+
+    Ok(flake
+        .split_once('#')
+        .map_or(&*flake, |(p, _)| p)
+        .to_string())
+}
+
+fn prompt_dev_shell(shells: &[String]) -> Result<String, Error> {
+    if shells.is_empty() {
+        return Err(anyhow!("the flake has no devShells for the current system"));
+    }
+
+    for (i, shell) in shells.iter().enumerate() {
+        eprintln!("{}) {}", i + 1, shell);
+    }
+
+    loop {
+        eprint!("select a dev shell [1-{}]: ", shells.len());
+
+        let mut line = String::new();
+        if std::io::stdin()
+            .read_line(&mut line)
+            .context("failed to read selection")?
+            == 0
+        {
+            return Err(anyhow!("no dev shell selected"));
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(i) if (1..=shells.len()).contains(&i) => return Ok(shells[i - 1].clone()),
+            _ => eprintln!("invalid selection: {}", line.trim()),
+        }
+    }
+}
 
-    let env = nix::get_dev_env(args.path)?;
+fn main() {
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(error::exit_code(&e));
+    }
+}
 
+fn run(args: Cli) -> Result<(), Error> {
     let mut config_file: Option<Config> = None;
     if let Some(file) = args.config_file {
         let reader = BufReader::new(File::open(&file).context("failed to open config file")?);
@@ -81,6 +343,173 @@ fn main() -> Result<(), Error> {
         );
     }
 
+    let mut nix_args: Vec<String> = Vec::new();
+    for config in config_file.iter().chain(config_str.iter()) {
+        nix_args.extend_from_slice(&config.nix_args);
+    }
+    nix_args.extend(args.nix_arg);
+    for arg in args.arg.chunks(2) {
+        nix_args.push("--arg".to_string());
+        nix_args.extend_from_slice(arg);
+    }
+    for arg in args.argstr.chunks(2) {
+        nix_args.push("--argstr".to_string());
+        nix_args.extend_from_slice(arg);
+    }
+
+    let timeout = args
+        .timeout
+        .or(config_str.as_ref().and_then(|c| c.timeout))
+        .or(config_file.as_ref().and_then(|c| c.timeout))
+        .map(Duration::from_secs);
+
+    if let Some(Commands::ListShells { path }) = args.command {
+        let path = match path {
+            Some(path) => Some(path),
+            None => discover_path()?,
+        };
+        let log = NixLog {
+            quiet: args.quiet,
+            file: args.nix_log,
+        };
+
+        for shell in nix::list_dev_shells(&flake_ref(path.as_deref())?, &nix_args, &log, timeout)? {
+            println!("{}", shell);
+        }
+
+        return Ok(());
+    }
+
+    let paths: Vec<Option<String>> = if !args.path.is_empty() {
+        args.path.into_iter().map(Some).collect()
+    } else if args.no_discover {
+        vec![None]
+    } else {
+        vec![discover_path()?]
+    };
+
+    // the shell would start on the exhausted stdin and exit right away
+    if args.env_file.as_deref() == Some(Path::new("-")) && !args.print && args.exec.is_empty() {
+        return Err(anyhow!(
+            "--env-file - reads stdin, which only works with --print or -- COMMAND"
+        ));
+    }
+
+    if paths.len() > 1 && (args.env_file.is_some() || args.from_profile.is_some()) {
+        return Err(anyhow!(
+            "--env-file and --from-profile load a single env, they can't be used with multiple paths"
+        ));
+    }
+
+    let paths = if args.dev_shell.is_some() || args.select_shell {
+        let [path] = paths.as_slice() else {
+            return Err(anyhow!(
+                "a devShell can only be selected with a single path"
+            ));
+        };
+        let flake = flake_ref(path.as_deref())?;
+
+        let name = match &args.dev_shell {
+            Some(name) => name.to_string(),
+            None => {
+                let log = NixLog {
+                    quiet: args.quiet,
+                    file: args.nix_log.clone(),
+                };
+                prompt_dev_shell(&nix::list_dev_shells(&flake, &nix_args, &log, timeout)?)?
+            }
+        };
+        vec![Some(format!("{}#{}", flake, name))]
+    } else {
+        paths
+    };
+
+    // denied if the config denies it for any of the dev shells
+    let shell_hook = !args.no_shell_hook
+        && paths.iter().all(|path| {
+            config_str
+                .iter()
+                .chain(config_file.iter())
+                .find_map(|config| config.shell_hook_allowed(path.as_deref()))
+                .unwrap_or(true)
+        });
+
+    // (path, env) of every dev shell
+    let mut envs: Vec<(Option<String>, Env)> = Vec::new();
+
+    if let Some(file) = &args.env_file {
+        envs.push((paths[0].clone(), FileSource::new(file.clone()).get_env()?));
+    } else if let Some(name) = &args.from_profile {
+        envs.push((paths[0].clone(), Profile::new(name)?.get_env()?));
+    } else {
+        // a saved profile only stands in for a single dev shell
+        let fallback_profile = args.profile.as_ref().filter(|_| paths.len() == 1);
+
+        for path in paths {
+            let cache = Cache::new(path.as_deref(), &nix_args, &args.watch)?;
+            let source = NixSource::new(
+                Installable::detect(path.as_deref()),
+                nix_args.clone(),
+                NixLog {
+                    quiet: args.quiet,
+                    file: args.nix_log.clone(),
+                },
+                timeout,
+            );
+
+            let env = match cache.get_or_insert(&source, args.refresh) {
+                Ok(env) => env,
+                Err(e) => {
+                    let timed_out = matches!(e.downcast_ref(), Some(NixError::Timeout { .. }));
+                    let Some(name) = fallback_profile.filter(|_| timed_out) else {
+                        return Err(e);
+                    };
+                    let env = Profile::new(name)?.load().map_err(|_| e)?;
+                    eprintln!("warning: nix timed out, using profile {}", name);
+                    env
+                }
+            };
+            envs.push((path, env));
+        }
+    }
+
+    let mut gc_roots: Vec<GcRoot> = Vec::new();
+    if !args.print && args.gc_root != GcRootPolicy::None {
+        for (path, env) in &envs {
+            let dir = gcroot::gc_root_dir(&cache::shell_key(path.as_deref()))?;
+            gc_roots.push(GcRoot::register(&dir, env)?);
+        }
+    }
+
+    let on_conflict = args
+        .on_conflict
+        .or(config_str.as_ref().and_then(|c| c.on_conflict))
+        .or(config_file.as_ref().and_then(|c| c.on_conflict))
+        .unwrap_or_default();
+
+    let separators = filter::list_separators(
+        &envs.iter().map(|(_, env)| env).collect::<Vec<_>>(),
+        config_file.as_ref(),
+        config_str.as_ref(),
+    );
+    let (env, conflicts) = merge::merge(
+        envs.into_iter()
+            .map(|(path, env)| (path.unwrap_or_else(|| ".".to_string()), env))
+            .collect(),
+        &separators,
+        on_conflict,
+    )?;
+
+    for conflict in conflicts {
+        eprintln!("warning: {}", conflict);
+    }
+
+    if let Some(name) = &args.profile {
+        Profile::new(name)?
+            .save(&env)
+            .context("failed to save profile")?;
+    }
+
     let mut filter_file: Option<Env> = None;
     if let Some(file) = args.filter_file_raw {
         let reader = BufReader::new(File::open(&file).context("failed to open filter file")?);
@@ -100,25 +529,62 @@ fn main() -> Result<(), Error> {
         );
     }
 
+    let keep: Vec<String> = config_file
+        .iter()
+        .chain(config_str.iter())
+        .flat_map(|config| config.keep.iter().cloned())
+        .collect();
+    // config_str wins over config_file
+    let path_precedence = config_file
+        .iter()
+        .chain(config_str.iter())
+        .flat_map(|config| config.path_precedence.clone())
+        .collect();
+
     let env = filter::filter(env, filter_file, filter_str, config_file, config_str)?;
 
-    let shell = if let Some(shell_type) = args.shell {
-        shell_type
-    } else {
-        let shell_path = std::env::var("SHELL").context("failed to read SHELL env var")?;
+    let options = ShellOptions {
+        export_functions: args.export_functions,
+        shell_hook,
+        prompt: args.prompt,
+        pure: args.pure,
+        keep,
+        path_precedence,
+    };
 
-        let shell = Path::new(&shell_path)
-            .file_name()
-            .ok_or("not a valid file name")
-            .map_err(|e| anyhow!(e))?
-            .to_str()
-            .ok_or("failed to convert file name to str")
-            .map_err(|e| anyhow!(e))?;
+    if let Some((program, program_args)) = args.exec.split_first() {
+        let mut command = shell::env_command(&env, program, &options);
+        command.args(program_args);
 
-        shell.to_string()
+        if !gc_roots.is_empty() && args.gc_root == GcRootPolicy::Remove {
+            let code = shell::run_forwarding_signals(&mut command)
+                .with_context(|| format!("failed to run {}", program))?;
+            for gc_root in gc_roots {
+                gc_root.remove()?;
+            }
+            std::process::exit(code);
+        }
+
+        return Err(Error::from(command.exec()).context(format!("failed to run {}", program)));
+    }
+
+    // keeps the full path, the shell isn't on the dev PATH of a pure shell
+    let shell = match args.shell {
+        Some(shell) => shell,
+        None => std::env::var("SHELL").context("failed to read SHELL env var")?,
     };
 
-    start_shell(&env, &shell, args.print).context("Failed to start the shell")?;
+    if !gc_roots.is_empty() && args.gc_root == GcRootPolicy::Remove {
+        println!("starting shell: {}", shell);
+        let code = shell::run_forwarding_signals(&mut shell_command(&env, &shell, &options)?)
+            .context("Failed to start the shell")?;
+        for gc_root in gc_roots {
+            gc_root.remove()?;
+        }
+        std::process::exit(code);
+    }
+
+    start_shell(&env, &shell, args.print, &options).context("Failed to start the shell")?;
 
     Ok(())
 }
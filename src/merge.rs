@@ -0,0 +1,274 @@
+use core::fmt;
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Error, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    nix::Env,
+    shell::{combine_path, VariableValue},
+};
+
+/// Which value is used when dev shells set a variable or function differently.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictResolution {
+    /// The first dev shell that sets it wins.
+    #[default]
+    First,
+    /// The last dev shell that sets it wins.
+    Last,
+    /// Fail if any dev shells conflict.
+    Error,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub kind: &'static str,
+    pub name: String,
+    pub kept: String,
+    pub dropped: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} is set by {} and {}, using {}",
+            self.kind, self.name, self.kept, self.dropped, self.kept
+        )
+    }
+}
+
+/// Merges the envs of several dev shells, each given with a name for the conflict report.
+/// List variables, given by their separator, are concatenated in the order of the envs,
+/// other variables and functions that differ are resolved with `resolution`.
+pub fn merge(
+    envs: Vec<(String, Env)>,
+    separators: &HashMap<String, String>,
+    resolution: ConflictResolution,
+) -> Result<(Env, Vec<Conflict>), Error> {
+    let mut envs = envs.into_iter();
+    let (first_source, mut merged) = envs.next().ok_or_else(|| anyhow!("no env to merge"))?;
+
+    let mut var_sources: HashMap<String, String> = HashMap::new();
+    let mut function_sources: HashMap<String, String> = HashMap::new();
+    for (k, _) in &merged.variables {
+        var_sources.insert(k.to_string(), first_source.clone());
+    }
+    for (k, _) in &merged.bash_functions {
+        function_sources.insert(k.to_string(), first_source.clone());
+    }
+
+    let mut conflicts = Vec::new();
+
+    for (source, env) in envs {
+        for (k, v) in env.variables {
+            let Some(current) = merged.variables.get_mut(&k) else {
+                merged.variables.add(k.clone(), v);
+                var_sources.insert(k, source.clone());
+                continue;
+            };
+
+            if let Some(separator) = separators.get(&k) {
+                if let (
+                    VariableValue::Exported { value } | VariableValue::Var { value },
+                    VariableValue::Exported { value: other } | VariableValue::Var { value: other },
+                ) = (&mut *current, &v)
+                {
+                    if !other.is_empty() {
+                        *value = combine_path(std::mem::take(value), other, separator);
+                    }
+                    continue;
+                }
+            }
+
+            if *current != v {
+                let current_source = var_sources.get(&k).cloned().unwrap_or_default();
+                conflicts.push(resolve(
+                    "variable",
+                    &k,
+                    current_source,
+                    source.clone(),
+                    resolution,
+                ));
+
+                if resolution == ConflictResolution::Last {
+                    *current = v;
+                    var_sources.insert(k, source.clone());
+                }
+            }
+        }
+
+        for (k, v) in env.bash_functions {
+            let Some(current) = merged.bash_functions.get_mut(&k) else {
+                merged.bash_functions.add(k.clone(), v);
+                function_sources.insert(k, source.clone());
+                continue;
+            };
+
+            if *current != v {
+                let current_source = function_sources.get(&k).cloned().unwrap_or_default();
+                conflicts.push(resolve(
+                    "function",
+                    &k,
+                    current_source,
+                    source.clone(),
+                    resolution,
+                ));
+
+                if resolution == ConflictResolution::Last {
+                    *current = v;
+                    function_sources.insert(k, source.clone());
+                }
+            }
+        }
+    }
+
+    if resolution == ConflictResolution::Error && !conflicts.is_empty() {
+        let mut message = String::from("dev shells conflict:");
+        for conflict in &conflicts {
+            message += &format!(
+                "\n    {} {}: {} and {}",
+                conflict.kind, conflict.name, conflict.kept, conflict.dropped
+            );
+        }
+        return Err(anyhow!(message));
+    }
+
+    Ok((merged, conflicts))
+}
+
+fn resolve(
+    kind: &'static str,
+    name: &str,
+    current: String,
+    new: String,
+    resolution: ConflictResolution,
+) -> Conflict {
+    let (kept, dropped) = match resolution {
+        ConflictResolution::Last => (new, current),
+        ConflictResolution::First | ConflictResolution::Error => (current, new),
+    };
+
+    Conflict {
+        kind,
+        name: name.to_string(),
+        kept,
+        dropped,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envs() -> Vec<(String, Env)> {
+        let project = r#"
+            {
+                "bashFunctions": { "func1": "body1", "func2": "body2" },
+                "variables": {
+                    "PATH": { "type": "exported", "value": "/project/bin" },
+                    "NIX_CFLAGS_COMPILE": { "type": "exported", "value": "-I/project/include" },
+                    "var1": { "type": "exported", "value": "project"},
+                    "var2": { "type": "var", "value": "same"}
+                }
+            }
+        "#;
+        let tools = r#"
+            {
+                "bashFunctions": { "func1": "other body", "func3": "body3" },
+                "variables": {
+                    "PATH": { "type": "exported", "value": "/tools/bin:/tools/sbin" },
+                    "NIX_CFLAGS_COMPILE": { "type": "exported", "value": "-I/tools/include -O2" },
+                    "var1": { "type": "exported", "value": "tools"},
+                    "var2": { "type": "var", "value": "same"},
+                    "var3": { "type": "var", "value": "value3"}
+                }
+            }
+        "#;
+
+        vec![
+            (
+                "project".to_string(),
+                serde_json::from_str(project).unwrap(),
+            ),
+            ("tools".to_string(), serde_json::from_str(tools).unwrap()),
+        ]
+    }
+
+    fn separators() -> HashMap<String, String> {
+        HashMap::from([
+            ("PATH".to_string(), ":".to_string()),
+            ("NIX_CFLAGS_COMPILE".to_string(), " ".to_string()),
+        ])
+    }
+
+    fn value(env: &Env, key: &str) -> String {
+        match env.variables.get(key) {
+            Some(VariableValue::Exported { value } | VariableValue::Var { value }) => {
+                value.to_string()
+            }
+            v => panic!("unexpected value for {}: {:?}", key, v),
+        }
+    }
+
+    #[test]
+    fn test_merge_first() {
+        let (env, conflicts) = merge(envs(), &separators(), ConflictResolution::First).unwrap();
+
+        assert_eq!(value(&env, "PATH"), "/project/bin:/tools/bin:/tools/sbin");
+        assert_eq!(
+            value(&env, "NIX_CFLAGS_COMPILE"),
+            "-I/project/include -I/tools/include -O2"
+        );
+        assert_eq!(value(&env, "var1"), "project");
+        assert_eq!(value(&env, "var2"), "same");
+        assert_eq!(value(&env, "var3"), "value3");
+        assert_eq!(env.bash_functions.get("func1").unwrap(), "body1");
+        assert!(env.bash_functions.contains(&"func2".to_string()));
+        assert!(env.bash_functions.contains(&"func3".to_string()));
+
+        assert_eq!(conflicts.len(), 2, "unexpected conflicts: {:?}", conflicts);
+        assert!(conflicts.contains(&Conflict {
+            kind: "variable",
+            name: "var1".to_string(),
+            kept: "project".to_string(),
+            dropped: "tools".to_string(),
+        }));
+        assert!(conflicts.contains(&Conflict {
+            kind: "function",
+            name: "func1".to_string(),
+            kept: "project".to_string(),
+            dropped: "tools".to_string(),
+        }));
+    }
+
+    #[test]
+    fn test_merge_last() {
+        let (env, conflicts) = merge(envs(), &separators(), ConflictResolution::Last).unwrap();
+
+        assert_eq!(value(&env, "PATH"), "/project/bin:/tools/bin:/tools/sbin");
+        assert_eq!(value(&env, "var1"), "tools");
+        assert_eq!(env.bash_functions.get("func1").unwrap(), "other body");
+        assert_eq!(conflicts.len(), 2, "unexpected conflicts: {:?}", conflicts);
+    }
+
+    #[test]
+    fn test_merge_error() {
+        let result = merge(envs(), &separators(), ConflictResolution::Error);
+
+        let message = format!("{:#}", result.expect_err("conflicts didn't fail"));
+        assert!(
+            message.contains("variable var1: project and tools"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("function func1: project and tools"),
+            "{}",
+            message
+        );
+    }
+}
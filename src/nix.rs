@@ -1,8 +1,20 @@
-use crate::shell::VariableValue;
+use crate::{error::NixError, shell::VariableValue, source::EnvSource};
 use anyhow::{anyhow, Context, Error};
 use core::fmt;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, process::Command};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BashFunctionsType(HashMap<String, String>);
@@ -18,6 +30,27 @@ impl BashFunctionsType {
     {
         self.0.retain(f)
     }
+
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.0.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut String> {
+        self.0.get_mut(key)
+    }
+
+    pub fn add(&mut self, key: String, value: String) {
+        self.0.insert(key, value);
+    }
+}
+
+impl IntoIterator for BashFunctionsType {
+    type Item = (String, String);
+    type IntoIter = std::collections::hash_map::IntoIter<String, String>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
 }
 
 impl<'a> IntoIterator for &'a BashFunctionsType {
@@ -56,6 +89,10 @@ impl VariablesType {
         self.0.get(key)
     }
 
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut VariableValue> {
+        self.0.get_mut(key)
+    }
+
     pub fn add(&mut self, key: String, value: VariableValue) {
         self.0.insert(key, value);
     }
@@ -76,6 +113,15 @@ impl VariablesType {
     }
 }
 
+impl IntoIterator for VariablesType {
+    type Item = (String, VariableValue);
+    type IntoIter = std::collections::hash_map::IntoIter<String, VariableValue>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
 impl<'a> IntoIterator for &'a VariablesType {
     type Item = <&'a HashMap<String, VariableValue> as IntoIterator>::Item;
     type IntoIter = <&'a HashMap<String, VariableValue> as IntoIterator>::IntoIter;
@@ -142,38 +188,668 @@ impl fmt::Display for Env {
     }
 }
 
-pub fn get_dev_env(path: Option<String>) -> Result<Env, Error> {
-    let mut command = Command::new("nix");
-    command.arg("print-dev-env").arg("--json");
+/// Files evaluated with `--file` when a directory has no flake.nix, in order of preference.
+const LEGACY_FILES: [&str; 2] = ["shell.nix", "default.nix"];
+
+/// What nix print-dev-env evaluates.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Installable {
+    /// A flake reference, None uses the flake in the current directory.
+    Flake(Option<String>),
+    /// A non-flake nix file and an optional attribute path in it.
+    File { file: PathBuf, attr: Option<String> },
+}
+
+impl Installable {
+    /// Flake references, directories containing a flake.nix and flake.nix files themselves
+    /// are evaluated as flakes. Other `.nix` files and directories with a shell.nix or
+    /// default.nix are evaluated with `--file`, an attribute can be selected with `file.nix#attr`.
+    pub fn detect(path: Option<&str>) -> Installable {
+        let Some(path) = path else {
+            return Installable::detect_dir(Path::new("."), None)
+                .unwrap_or(Installable::Flake(None));
+        };
+
+        let (file, attr) = match path.split_once('#') {
+            Some((file, attr)) => (file, Some(attr.to_string())),
+            None => (path, None),
+        };
+
+        // flake urls like github:owner/repo or path:/some/dir
+        if file.contains(':') {
+            return Installable::Flake(Some(path.to_string()));
+        }
+
+        let file_path = Path::new(if file.is_empty() { "." } else { file });
+
+        if file_path.is_file() && file_path.ends_with("flake.nix") {
+            let mut flake = flake_dir(file_path);
+            if let Some(attr) = attr {
+                flake = format!("{}#{}", flake, attr);
+            }
+            Installable::Flake(Some(flake))
+        } else if file_path.is_file() && file.ends_with(".nix") {
+            Installable::File {
+                file: file_path.to_path_buf(),
+                attr,
+            }
+        } else if file_path.is_dir() {
+            Installable::detect_dir(file_path, attr)
+                .unwrap_or(Installable::Flake(Some(path.to_string())))
+        } else {
+            Installable::Flake(Some(path.to_string()))
+        }
+    }
+
+    /// Returns None for flakes.
+    fn detect_dir(dir: &Path, attr: Option<String>) -> Option<Installable> {
+        if dir.join("flake.nix").exists() {
+            return None;
+        }
+
+        LEGACY_FILES
+            .iter()
+            .map(|f| dir.join(f))
+            .find(|f| f.is_file())
+            .map(|file| Installable::File { file, attr })
+    }
+
+    fn args(&self) -> Vec<OsString> {
+        match self {
+            Installable::Flake(path) => path.iter().map(OsString::from).collect(),
+            Installable::File { file, attr } => {
+                let mut args = vec![OsString::from("--file"), file.into()];
+                args.extend(attr.iter().map(OsString::from));
+                args
+            }
+        }
+    }
+}
+
+/// The flake reference of the directory of a flake.nix, relative paths need a `./`
+/// or nix looks them up in the flake registry.
+fn flake_dir(flake_nix: &Path) -> String {
+    let dir = flake_nix.parent().unwrap_or(Path::new("."));
+    if dir.as_os_str().is_empty() {
+        ".".to_string()
+    } else if dir.is_absolute() || dir.starts_with(".") || dir.starts_with("..") {
+        dir.display().to_string()
+    } else {
+        format!("./{}", dir.display())
+    }
+}
+
+/// Files that mark the root of a dev shell when searching parent directories.
+const ROOT_FILES: [&str; 2] = ["flake.nix", "shell.nix"];
+
+/// Walks up from `start` to the nearest directory containing a flake.nix or shell.nix,
+/// without leaving the git repository `start` is in.
+/// Returns the file that was found.
+pub fn discover(start: &Path) -> Option<PathBuf> {
+    for dir in start.ancestors() {
+        if let Some(file) = ROOT_FILES.iter().map(|f| dir.join(f)).find(|f| f.is_file()) {
+            return Some(file);
+        }
+
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+
+    None
+}
+
+/// Evaluates the dev shell with `nix print-dev-env --json`.
+pub struct NixSource {
+    installable: Installable,
+    args: Vec<String>,
+    log: NixLog,
+    timeout: Option<Duration>,
+}
+
+impl NixSource {
+    /// `args` are passed verbatim to nix, e.g. `--impure` or `--override-input`.
+    /// nix is killed if it runs longer than `timeout`.
+    pub fn new(
+        installable: Installable,
+        args: Vec<String>,
+        log: NixLog,
+        timeout: Option<Duration>,
+    ) -> NixSource {
+        NixSource {
+            installable,
+            args,
+            log,
+            timeout,
+        }
+    }
+}
+
+impl EnvSource for NixSource {
+    fn get_env(&self) -> Result<Env, Error> {
+        get_dev_env(&self.installable, &self.args, &self.log, self.timeout)
+    }
+}
+
+/// Oldest nix release whose `print-dev-env` supports `--json`.
+const MIN_NIX_VERSION: (u32, u32) = (2, 8);
+
+/// Parses the version from `nix --version` output like `nix (Nix) 2.18.1`.
+fn parse_nix_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.split_whitespace().last()?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor: String = parts
+        .next()?
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+
+    Some((major, minor.parse().ok()?))
+}
+
+/// Checks that the installed nix supports `print-dev-env --json`.
+fn check_nix_version() -> Result<(), Error> {
+    let output = Command::new("nix")
+        .arg("--version")
+        .output()
+        .map_err(spawn_error)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    // unknown version formats are let through, nix will complain if it can't handle the call
+    if let Some(version) = parse_nix_version(&stdout) {
+        if version < MIN_NIX_VERSION {
+            return Err(NixError::TooOld {
+                version: stdout.trim().to_string(),
+                required: MIN_NIX_VERSION,
+            }
+            .into());
+        }
+    }
 
-    if let Some(path) = path.as_ref() {
-        command.arg(path);
+    Ok(())
+}
+
+fn spawn_error(e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::NotFound {
+        NixError::NotFound.into()
+    } else {
+        Error::new(e).context("failed to run nix")
     }
+}
 
-    let output = command.output().context("nix print-dev-env failed.")?;
+/// The nix config from `nix show-config --json`, Null if it can't be read.
+fn nix_config() -> serde_json::Value {
+    // show-config is itself part of nix-command
+    let output = Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command"])
+        .args(["show-config", "--json"])
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => {
+            serde_json::from_slice(&output.stdout).unwrap_or_default()
+        }
+        _ => serde_json::Value::Null,
+    }
+}
+
+/// Experimental features enabled in the nix config.
+fn enabled_features(config: &serde_json::Value) -> Vec<String> {
+    config["experimental-features"]["value"]
+        .as_array()
+        .map(|features| {
+            features
+                .iter()
+                .filter_map(|f| f.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The system nix builds for, e.g. `x86_64-linux`.
+fn current_system(config: &serde_json::Value) -> Result<String, Error> {
+    config["system"]["value"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("failed to read the current system from nix show-config"))
+}
+
+/// Names of the devShells a flake provides for the current system,
+/// evaluated with the same `args` as the dev shell itself.
+pub fn list_dev_shells(
+    flake: &str,
+    args: &[String],
+    log: &NixLog,
+    timeout: Option<Duration>,
+) -> Result<Vec<String>, Error> {
+    let config = nix_config();
+    let system = current_system(&config)?;
+    let installable = Installable::Flake(Some(flake.to_string()));
+
+    let mut command = Command::new("nix");
+    command
+        .args(feature_args(&installable, &enabled_features(&config)))
+        .args(["eval", "--json", "--apply", "builtins.attrNames"])
+        .args(args)
+        .arg(format!("{}#devShells.{}", flake, system));
+
+    let output = run_nix(&mut command, log, timeout)?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!("{}", stderr));
+        return Err(NixError::from_stderr(&stderr, log.quiet)).context("failed to list dev shells");
+    }
+
+    serde_json::from_slice(&output.stdout).context("failed to deserialize dev shell names")
+}
+
+/// `--extra-experimental-features` for the features the installable needs
+/// that aren't enabled in the nix config.
+fn feature_args(installable: &Installable, enabled: &[String]) -> Vec<String> {
+    let mut needed = vec!["nix-command"];
+    if let Installable::Flake(_) = installable {
+        needed.push("flakes");
     }
 
-    let output_json = String::from_utf8_lossy(&output.stdout);
+    let missing: Vec<&str> = needed
+        .into_iter()
+        .filter(|f| !enabled.iter().any(|e| e == f))
+        .collect();
+
+    if missing.is_empty() {
+        Vec::new()
+    } else {
+        vec![
+            "--extra-experimental-features".to_string(),
+            missing.join(" "),
+        ]
+    }
+}
+
+/// Where the stderr of nix goes while it runs.
+#[derive(Debug, Default)]
+pub struct NixLog {
+    /// Don't stream the output to the terminal.
+    pub quiet: bool,
+    /// Also write the output to this file.
+    pub file: Option<PathBuf>,
+}
+
+struct NixOutput {
+    status: ExitStatus,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// Runs the command while streaming its stderr according to `log`,
+/// stdout and stderr are also captured.
+fn run_nix(
+    command: &mut Command,
+    log: &NixLog,
+    timeout: Option<Duration>,
+) -> Result<NixOutput, Error> {
+    let mut log_file = log
+        .file
+        .as_ref()
+        .map(|file| {
+            File::create(file)
+                .with_context(|| format!("failed to create nix log file: {}", file.display()))
+        })
+        .transpose()?;
+
+    // a Ctrl-C in the terminal also reaches nix, SIGTERM only reaches us
+    let interrupt = InterruptGuard::new()?;
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error)?;
+
+    let mut child_stderr = child.stderr.take().expect("stderr is piped");
+    let quiet = log.quiet;
+
+    // forwarded as it arrives instead of line by line, so progress output isn't delayed
+    let stderr_thread = thread::spawn(move || -> io::Result<Vec<u8>> {
+        let mut captured = Vec::new();
+        let mut buf = [0; 4096];
+
+        loop {
+            let n = child_stderr.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            if !quiet {
+                let mut stderr = io::stderr().lock();
+                stderr.write_all(&buf[..n])?;
+                stderr.flush()?;
+            }
+            if let Some(file) = log_file.as_mut() {
+                file.write_all(&buf[..n])?;
+            }
+            captured.extend_from_slice(&buf[..n]);
+        }
+
+        Ok(captured)
+    });
+
+    let mut child_stdout = child.stdout.take().expect("stdout is piped");
+    let stdout_thread = thread::spawn(move || -> io::Result<Vec<u8>> {
+        let mut stdout = Vec::new();
+        child_stdout.read_to_end(&mut stdout)?;
+        Ok(stdout)
+    });
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let status = loop {
+        // the output threads are left behind, processes started by nix may keep the pipes open
+        if let Some(status) = child.try_wait().context("failed to wait for nix")? {
+            // nix failing because it got the Ctrl-C too isn't an evaluation error
+            if interrupt.interrupted() {
+                return Err(NixError::Interrupted.into());
+            }
+            break status;
+        }
+
+        if interrupt.interrupted() {
+            terminate(&mut child)?;
+            return Err(NixError::Interrupted.into());
+        }
+        if let (Some(deadline), Some(timeout)) = (deadline, timeout) {
+            if Instant::now() >= deadline {
+                terminate(&mut child)?;
+                return Err(NixError::Timeout { timeout }.into());
+            }
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let stdout = stdout_thread
+        .join()
+        .map_err(|_| anyhow!("nix stdout thread panicked"))?
+        .context("failed to read nix output")?;
+    let stderr = stderr_thread
+        .join()
+        .map_err(|_| anyhow!("nix stderr thread panicked"))?
+        .context("failed to forward nix output")?;
+
+    Ok(NixOutput {
+        status,
+        stdout,
+        stderr,
+    })
+}
 
-    let mut env: Env = serde_json::from_str(&output_json)?;
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-    // prevents gc while in the shell
-    if let Some(store_path) = env.variables.get("out") {
-        match store_path {
-            VariableValue::Exported { value } | VariableValue::Var { value } => {
-                let owned = value.to_owned();
-                env.variables.add(
-                    "NIX_GCROOT".to_string(),
-                    VariableValue::Var { value: owned },
-                )
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/// Catches SIGINT and SIGTERM while nix runs, the previous handlers are restored on drop
+/// so the rest of the run reacts to them as usual.
+struct InterruptGuard {
+    previous: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+impl InterruptGuard {
+    fn new() -> Result<InterruptGuard, Error> {
+        INTERRUPTED.store(false, Ordering::Relaxed);
+
+        let mut guard = InterruptGuard {
+            previous: Vec::new(),
+        };
+        for signal in [SIGINT, SIGTERM] {
+            // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_interrupt as extern "C" fn(libc::c_int) as usize;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut previous: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(signal, &action, &mut previous) != 0 {
+                    return Err(io::Error::last_os_error())
+                        .context("failed to register signal handler");
+                }
+                guard.previous.push((signal, previous));
             }
-            VariableValue::Array { value: _ } | VariableValue::Associative { value: _ } => {}
         }
+
+        Ok(guard)
+    }
+
+    fn interrupted(&self) -> bool {
+        INTERRUPTED.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        for (signal, previous) in &self.previous {
+            // SAFETY: restores a handler that sigaction returned before
+            unsafe { libc::sigaction(*signal, previous, std::ptr::null_mut()) };
+        }
+    }
+}
+
+/// Asks nix to exit with SIGTERM, and kills it if it is still running after a grace period.
+fn terminate(child: &mut Child) -> Result<(), Error> {
+    const GRACE_PERIOD: Duration = Duration::from_secs(3);
+
+    if let Ok(pid) = libc::pid_t::try_from(child.id()) {
+        // SAFETY: kill has no memory safety requirements, the pid is our unreaped child
+        unsafe { libc::kill(pid, SIGTERM) };
+    }
+
+    let deadline = Instant::now() + GRACE_PERIOD;
+    while Instant::now() < deadline {
+        if child
+            .try_wait()
+            .context("failed to wait for nix")?
+            .is_some()
+        {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(50));
     }
 
+    child.kill().context("failed to kill nix")?;
+    child.wait().context("failed to wait for nix")?;
+
+    Ok(())
+}
+
+pub fn get_dev_env(
+    installable: &Installable,
+    args: &[String],
+    log: &NixLog,
+    timeout: Option<Duration>,
+) -> Result<Env, Error> {
+    check_nix_version()?;
+
+    let mut command = Command::new("nix");
+    command
+        .args(feature_args(installable, &enabled_features(&nix_config())))
+        .arg("print-dev-env")
+        .arg("--json")
+        .args(args)
+        .args(installable.args());
+
+    let output = run_nix(&mut command, log, timeout)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // the output was already streamed to the terminal unless quiet
+        return Err(NixError::from_stderr(&stderr, log.quiet).into());
+    }
+
+    let output_json = String::from_utf8(output.stdout).map_err(|_| NixError::NonUtf8Output)?;
+
+    let env: Env = serde_json::from_str(&output_json).map_err(NixError::UnsupportedSchema)?;
+
     Ok(env)
 }
+
+/// Reads a dev env in the `nix print-dev-env --json` format from a file.
+/// `-` reads from stdin.
+pub fn read_dev_env(file: &Path) -> Result<Env, Error> {
+    let mut json = String::new();
+
+    if file == Path::new("-") {
+        io::stdin()
+            .read_to_string(&mut json)
+            .context("failed to read env from stdin")?;
+    } else {
+        BufReader::new(
+            File::open(file)
+                .with_context(|| format!("failed to open env file: {}", file.display()))?,
+        )
+        .read_to_string(&mut json)
+        .with_context(|| format!("failed to read env file: {}", file.display()))?;
+    }
+
+    serde_json::from_str(&json).with_context(|| format!("failed to deserialize env:\n{}", json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_read_dev_env() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+            {{
+                "bashFunctions": {{ "func1": "body1" }},
+                "variables": {{
+                    "var1": {{ "type": "exported", "value": "value1"}},
+                    "var2": {{ "type": "array", "value": ["1", "2"]}}
+                }}
+            }}
+        "#
+        )
+        .unwrap();
+
+        let env = read_dev_env(file.path());
+        assert!(env.is_ok(), "read_dev_env failed: {:#}", env.unwrap_err());
+
+        let env = env.unwrap();
+        assert!(env.bash_functions.contains(&"func1".to_string()));
+        assert!(env.variables.contains(&"var1".to_string()));
+        assert!(env.variables.contains(&"var2".to_string()));
+
+        write!(file, "not json").unwrap();
+        assert!(read_dev_env(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_parse_nix_version() {
+        assert_eq!(parse_nix_version("nix (Nix) 2.18.1\n"), Some((2, 18)));
+        assert_eq!(
+            parse_nix_version("nix (Lix, like Nix) 2.90.0"),
+            Some((2, 90))
+        );
+        assert_eq!(
+            parse_nix_version("nix (Nix) 2.25pre20241010_dirty"),
+            Some((2, 25))
+        );
+        assert_eq!(parse_nix_version("nix (Nix) 2.3.16"), Some((2, 3)));
+        assert_eq!(parse_nix_version("garbage"), None);
+    }
+
+    #[test]
+    fn test_feature_args() {
+        let flake = Installable::Flake(None);
+        let file = Installable::File {
+            file: PathBuf::from("shell.nix"),
+            attr: None,
+        };
+
+        assert_eq!(
+            feature_args(&flake, &[]),
+            ["--extra-experimental-features", "nix-command flakes"]
+        );
+        assert_eq!(
+            feature_args(&flake, &["nix-command".to_string()]),
+            ["--extra-experimental-features", "flakes"]
+        );
+        assert_eq!(
+            feature_args(&file, &[]),
+            ["--extra-experimental-features", "nix-command"]
+        );
+        assert!(feature_args(&file, &["nix-command".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn test_discover() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        let sub = repo.join("a").join("b");
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+
+        assert_eq!(discover(&sub), None);
+
+        // outside of the git repo
+        std::fs::write(dir.path().join("flake.nix"), "{}").unwrap();
+        assert_eq!(discover(&sub), None);
+
+        std::fs::write(repo.join("shell.nix"), "{}").unwrap();
+        assert_eq!(discover(&sub), Some(repo.join("shell.nix")));
+
+        std::fs::write(repo.join("a").join("flake.nix"), "{}").unwrap();
+        assert_eq!(discover(&sub), Some(repo.join("a").join("flake.nix")));
+    }
+
+    #[test]
+    fn test_detect_installable() {
+        let dir = tempfile::tempdir().unwrap();
+        let flake = dir.path().join("flake");
+        let legacy = dir.path().join("legacy");
+        std::fs::create_dir_all(&flake).unwrap();
+        std::fs::create_dir_all(&legacy).unwrap();
+        std::fs::write(flake.join("flake.nix"), "{}").unwrap();
+        std::fs::write(legacy.join("default.nix"), "{}").unwrap();
+        std::fs::write(legacy.join("shell.nix"), "{}").unwrap();
+
+        let flake = flake.to_str().unwrap();
+        let legacy = legacy.to_str().unwrap();
+
+        assert_eq!(
+            Installable::detect(Some(flake)),
+            Installable::Flake(Some(flake.to_string()))
+        );
+        assert_eq!(
+            Installable::detect(Some(&format!("{}/flake.nix#ci", flake))),
+            Installable::Flake(Some(format!("{}#ci", flake)))
+        );
+        assert_eq!(flake_dir(Path::new("flake.nix")), ".");
+        assert_eq!(flake_dir(Path::new("sub/flake.nix")), "./sub");
+        assert_eq!(flake_dir(Path::new("../sub/flake.nix")), "../sub");
+        assert_eq!(
+            Installable::detect(Some("github:NixOS/nixpkgs#hello")),
+            Installable::Flake(Some("github:NixOS/nixpkgs#hello".to_string()))
+        );
+        assert_eq!(
+            Installable::detect(Some(legacy)),
+            Installable::File {
+                file: Path::new(legacy).join("shell.nix"),
+                attr: None
+            }
+        );
+        assert_eq!(
+            Installable::detect(Some(&format!("{}/default.nix#ci", legacy))),
+            Installable::File {
+                file: Path::new(legacy).join("default.nix"),
+                attr: Some("ci".to_string())
+            }
+        );
+    }
+}
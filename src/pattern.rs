@@ -0,0 +1,137 @@
+use anyhow::{anyhow, Context, Error};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Matches a variable name or list entry in config and filter files.
+/// `glob:` selects glob syntax and `re:` a regex that has to match the whole string,
+/// anything else is matched literally.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Literal(String),
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Pattern, Error> {
+        if let Some(glob) = pattern.strip_prefix("glob:") {
+            glob::Pattern::new(glob)
+                .map(Pattern::Glob)
+                .with_context(|| format!("invalid glob {}", glob))
+        } else if let Some(regex) = pattern.strip_prefix("re:") {
+            Regex::new(&format!("^(?:{})$", regex))
+                .map(Pattern::Regex)
+                .with_context(|| format!("invalid regex {}", regex))
+        } else {
+            Ok(Pattern::Literal(pattern.to_string()))
+        }
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            Pattern::Literal(literal) => literal == value,
+            Pattern::Glob(glob) => glob.matches(value),
+            Pattern::Regex(regex) => regex.is_match(value),
+        }
+    }
+
+    pub fn as_literal(&self) -> Option<&str> {
+        match self {
+            Pattern::Literal(literal) => Some(literal),
+            _ => None,
+        }
+    }
+}
+
+/// A list of patterns, where entries starting with `!` are exceptions.
+/// Exceptions take precedence over the other entries regardless of their order,
+/// e.g. `["glob:NIX_*", "!NIX_CC"]` matches every `NIX_` variable except NIX_CC.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct Patterns {
+    source: Vec<String>,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Patterns {
+    pub fn new<S: AsRef<str>>(patterns: impl IntoIterator<Item = S>) -> Result<Patterns, Error> {
+        let mut res = Patterns::default();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            res.source.push(pattern.to_string());
+            match pattern.strip_prefix('!') {
+                Some(exception) => res.exclude.push(Pattern::new(exception)?),
+                None => res.include.push(Pattern::new(pattern)?),
+            }
+        }
+
+        Ok(res)
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.include.iter().any(|p| p.is_match(value))
+            && !self.exclude.iter().any(|p| p.is_match(value))
+    }
+
+    /// The literal entries, they match even if a value isn't known up front.
+    pub fn literals(&self) -> impl Iterator<Item = &str> {
+        self.include
+            .iter()
+            .filter_map(Pattern::as_literal)
+            .filter(|literal| self.is_match(literal))
+    }
+}
+
+impl TryFrom<Vec<String>> for Patterns {
+    type Error = Error;
+
+    fn try_from(patterns: Vec<String>) -> Result<Patterns, Error> {
+        // serde only keeps the message, so include the cause in it
+        Patterns::new(patterns).map_err(|e| anyhow!("{:#}", e))
+    }
+}
+
+impl From<Patterns> for Vec<String> {
+    fn from(patterns: Patterns) -> Vec<String> {
+        patterns.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern() {
+        assert!(Pattern::new("NIX_CC").unwrap().is_match("NIX_CC"));
+        assert!(!Pattern::new("NIX_*").unwrap().is_match("NIX_CC"));
+        assert!(Pattern::new("glob:NIX_*").unwrap().is_match("NIX_CC"));
+        assert!(Pattern::new("glob:/nix/store/*-gcc-wrapper-*/bin")
+            .unwrap()
+            .is_match("/nix/store/abc-gcc-wrapper-13.2.0/bin"));
+        assert!(Pattern::new("re:NIX_(CC|LD)").unwrap().is_match("NIX_LD"));
+        assert!(!Pattern::new("re:NIX").unwrap().is_match("NIX_LD"));
+        assert!(Pattern::new("re:(").is_err());
+        assert!(Pattern::new("glob:[").is_err());
+    }
+
+    #[test]
+    fn test_patterns() {
+        let patterns = Patterns::new(["!NIX_CC", "glob:NIX_*", "var"]).unwrap();
+
+        assert!(patterns.is_match("NIX_CFLAGS_COMPILE"));
+        assert!(patterns.is_match("var"));
+        assert!(!patterns.is_match("NIX_CC"));
+        assert!(!patterns.is_match("PATH"));
+        assert_eq!(patterns.literals().collect::<Vec<_>>(), ["var"]);
+
+        let patterns: Patterns = serde_json::from_str(r#"["re:a.*", "!ab"]"#).unwrap();
+        assert!(patterns.is_match("ac"));
+        assert!(!patterns.is_match("ab"));
+        assert_eq!(
+            serde_json::to_string(&patterns).unwrap(),
+            r#"["re:a.*","!ab"]"#
+        );
+    }
+}
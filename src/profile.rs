@@ -0,0 +1,66 @@
+use crate::{
+    cache::write_json,
+    gcroot::GcRoot,
+    nix::{read_dev_env, Env},
+    source::EnvSource,
+};
+use anyhow::{Context, Error, Result};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// A saved dev env that can be entered again without evaluating nix.
+/// The env is stored in the `nix print-dev-env --json` format next to
+/// gc roots that keep its store paths alive.
+pub struct Profile {
+    dir: PathBuf,
+}
+
+impl Profile {
+    /// `name` is either a path (if it contains a `/`) or the name of a
+    /// profile in `$XDG_DATA_HOME/nix-dev-env/profiles`.
+    pub fn new(name: &str) -> Result<Profile, Error> {
+        let dir = if name.contains('/') {
+            PathBuf::from(name)
+        } else {
+            profiles_dir()?.join(name)
+        };
+
+        Ok(Profile { dir })
+    }
+
+    fn env_file(&self) -> PathBuf {
+        self.dir.join("env.json")
+    }
+
+    pub fn load(&self) -> Result<Env, Error> {
+        read_dev_env(&self.env_file()).context("failed to load profile")
+    }
+
+    pub fn save(&self, env: &Env) -> Result<(), Error> {
+        write_json(&self.env_file(), env)?;
+        GcRoot::register(&self.dir.join("gcroots"), env)?;
+
+        Ok(())
+    }
+}
+
+impl EnvSource for Profile {
+    fn get_env(&self) -> Result<Env, Error> {
+        self.load()
+    }
+}
+
+/// `$XDG_DATA_HOME/nix-dev-env/profiles`, falling back to `$HOME/.local/share/nix-dev-env/profiles`.
+pub fn profiles_dir() -> Result<PathBuf, Error> {
+    let base = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = env::var_os("HOME").context("neither XDG_DATA_HOME nor HOME is set")?;
+            Path::new(&home).join(".local").join("share")
+        }
+    };
+
+    Ok(base.join("nix-dev-env").join("profiles"))
+}
@@ -1,13 +1,22 @@
 use crate::filter::FinalEnv;
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Context, Error, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::env;
+use std::ffi::OsStr;
+use std::fs;
 use std::io::stdout;
 use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::thread;
 use std::{os::unix::process::CommandExt, process::Command};
 
-#[derive(Serialize, Deserialize, Debug)]
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum VariableValue {
     Exported { value: String },
@@ -24,21 +33,883 @@ pub fn combine_path(a: String, b: &str, split: &str) -> String {
     }
 }
 
-pub fn start_shell(env: &FinalEnv, shell: &String, only_print: bool) -> Result<(), Error> {
-    let mut command = Command::new(shell);
+/// How the dev value of a path variable is combined with the inherited value.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PathPrecedence {
+    /// The dev paths come first, like nix develop.
+    #[default]
+    Prepend,
+    /// The inherited paths come first.
+    Append,
+    /// Only the dev paths are used.
+    Replace,
+}
 
-    for (k, v) in &env.variables {
-        command.env(k, v);
+/// Combines the `inherited` and `dev` lists.
+/// In `:` separated path lists every entry is only kept where it appears first. Other lists
+/// like flags can repeat entries, e.g. `-isystem`, so `dev` is only skipped as a whole if
+/// `inherited` already contains it.
+pub fn merge_paths(
+    inherited: &str,
+    dev: &str,
+    separator: &str,
+    precedence: PathPrecedence,
+) -> String {
+    if separator != ":" {
+        let contained = format!("{0}{1}{0}", separator, inherited)
+            .contains(&format!("{0}{1}{0}", separator, dev));
+        let values = match precedence {
+            PathPrecedence::Replace => vec![dev],
+            _ if contained => vec![inherited],
+            PathPrecedence::Prepend => vec![dev, inherited],
+            PathPrecedence::Append => vec![inherited, dev],
+        };
+        return values
+            .into_iter()
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>()
+            .join(separator);
     }
 
-    for (k, v) in &env.paths {
-        if let Ok(env_var) = env::var(k) {
-            command.env(k, combine_path(env_var, v, ":"));
+    let paths: Vec<&str> = match precedence {
+        PathPrecedence::Prepend => dev
+            .split(separator)
+            .chain(inherited.split(separator))
+            .collect(),
+        PathPrecedence::Append => inherited
+            .split(separator)
+            .chain(dev.split(separator))
+            .collect(),
+        PathPrecedence::Replace => dev.split(separator).collect(),
+    };
+
+    let mut merged: Vec<&str> = Vec::new();
+    for path in paths {
+        if !path.is_empty() && !merged.contains(&path) {
+            merged.push(path);
+        }
+    }
+
+    merged.join(separator)
+}
+
+/// Quotes a string for POSIX shells.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Escapes a string for use inside double quotes in POSIX shells.
+fn escape_double_quoted(s: &str) -> String {
+    s.chars()
+        .flat_map(|c| match c {
+            '\\' | '"' | '$' | '`' => vec!['\\', c],
+            c => vec![c],
+        })
+        .collect()
+}
+
+/// Quotes a string for fish.
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+/// Escapes a string for use inside double quotes in fish.
+fn escape_fish_double_quoted(s: &str) -> String {
+    s.chars()
+        .flat_map(|c| match c {
+            '\\' | '"' | '$' => vec!['\\', c],
+            c => vec![c],
+        })
+        .collect()
+}
+
+/// Quotes a string for nushell.
+fn nu_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', r"\\").replace('"', "\\\""))
+}
+
+fn shell_name(shell: &str) -> &str {
+    Path::new(shell)
+        .file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or(shell)
+}
+
+/// Shells that can be started, by the name of their executable.
+pub const SUPPORTED_SHELLS: [&str; 5] = ["bash", "zsh", "ksh", "fish", "nu"];
+
+/// How the shell is started.
+#[derive(Debug, Default)]
+pub struct ShellOptions {
+    /// Also export the functions as `BASH_FUNC_name%%`, so bash started from the shell
+    /// inherits them.
+    pub export_functions: bool,
+    /// Run the shellHook of the dev shell, if it has one.
+    pub shell_hook: bool,
+    /// Put this in front of the prompt.
+    pub prompt: Option<String>,
+    /// Start from an empty environment instead of the inherited one, without the user's
+    /// startup files.
+    pub pure: bool,
+    /// Variables kept from the inherited environment in pure mode, besides `PURE_KEEP`.
+    pub keep: Vec<String>,
+    /// How path variables are combined with the inherited value, prepend if not set.
+    pub path_precedence: HashMap<String, PathPrecedence>,
+}
+
+/// Variables that are always kept from the inherited environment in pure mode.
+pub const PURE_KEEP: [&str; 4] = ["HOME", "USER", "TERM", "DISPLAY"];
+
+/// Variables of the build environment that nix develop doesn't set in the shell either,
+/// some are read-only in bash or have side effects like UID in zsh.
+pub const IGNORED_VARS: [&str; 16] = [
+    "BASHOPTS",
+    "HOME",
+    "NIX_BUILD_TOP",
+    "NIX_ENFORCE_PURITY",
+    "NIX_LOG_FD",
+    "NIX_REMOTE",
+    "PPID",
+    "SHELLOPTS",
+    "SSL_CERT_FILE",
+    "TEMP",
+    "TEMPDIR",
+    "TERM",
+    "TMP",
+    "TMPDIR",
+    "TZ",
+    "UID",
+];
+
+/// The syntax of a shell, and how to make it run an init script on startup.
+/// The provided methods generate POSIX shell code, used as is by zsh and ksh.
+pub trait ShellBackend {
+    /// Makes `command` run `script` after the user's own startup files,
+    /// which are skipped if `user_config` is false.
+    fn add_init_script(
+        &self,
+        command: &mut Command,
+        script: &str,
+        user_config: bool,
+    ) -> Result<(), Error>;
+
+    fn set_variable(&self, name: &str, value: &str) -> String {
+        format!("export {}={}\n", name, quote(value))
+    }
+
+    /// Puts `path` in front of the list variable `name`. A path that is already in a `:`
+    /// separated list is moved to the front, so the startup files can't shadow it.
+    /// For other lists `path` is the whole dev value, which is kept if it's already in there.
+    fn prepend_path(&self, name: &str, path: &str, separator: &str) -> String {
+        if separator == ":" {
+            return format!(
+                "_nix_dev_env_paths=\":${{{name}-}}:\"; \
+                 while case \"$_nix_dev_env_paths\" in *{pattern}*) ;; *) false ;; esac; do \
+                 _nix_dev_env_paths=${{_nix_dev_env_paths%%{pattern}*}}:${{_nix_dev_env_paths#*{pattern}}}; done; \
+                 _nix_dev_env_paths=${{_nix_dev_env_paths#:}}; _nix_dev_env_paths=${{_nix_dev_env_paths%:}}; \
+                 export {name}={path}\"${{_nix_dev_env_paths:+:$_nix_dev_env_paths}}\"; unset _nix_dev_env_paths\n",
+                name = name,
+                pattern = quote(&format!(":{}:", path)),
+                path = quote(path)
+            );
+        }
+
+        format!(
+            "case \"{sep}${{{name}-}}{sep}\" in *{pattern}*) ;; *) export {name}={path}\"${{{name}:+{sep}${name}}}\" ;; esac\n",
+            name = name,
+            sep = escape_double_quoted(separator),
+            pattern = quote(&format!("{}{}{}", separator, path, separator)),
+            path = quote(path)
+        )
+    }
+
+    /// Puts `path` at the end of the list variable `name`, unless it is already in there.
+    fn append_path(&self, name: &str, path: &str, separator: &str) -> String {
+        format!(
+            "case \"{sep}${{{name}-}}{sep}\" in *{pattern}*) ;; *) export {name}=\"${{{name}:+${name}{sep}}}\"{path} ;; esac\n",
+            name = name,
+            sep = escape_double_quoted(separator),
+            pattern = quote(&format!("{}{}{}", separator, path, separator)),
+            path = quote(path)
+        )
+    }
+
+    /// Sets the list variable `name` to exactly `paths`.
+    fn set_path(&self, name: &str, paths: &[&str], separator: &str) -> String {
+        self.set_variable(name, &paths.join(separator))
+    }
+
+    /// None if the shell has no arrays.
+    fn set_array(&self, name: &str, values: &[String]) -> Option<String> {
+        let values: Vec<String> = values.iter().map(|v| quote(v)).collect();
+        Some(format!("typeset -a {}=({})\n", name, values.join(" ")))
+    }
+
+    /// None if the shell has no associative arrays.
+    fn set_associative_array(&self, name: &str, values: &[(&String, &String)]) -> Option<String> {
+        let values: Vec<String> = values
+            .iter()
+            .map(|(k, v)| format!("[{}]={}", quote(k), quote(v)))
+            .collect();
+        Some(format!("typeset -A {}=({})\n", name, values.join(" ")))
+    }
+
+    /// The bash function `name`, by default a wrapper that runs it in bash.
+    fn define_function(&self, name: &str, _body: &str) -> String {
+        format!(
+            "{} () {{ bash -c {} {} \"$@\"; }}\n",
+            name,
+            quote(&format!("{} \"$@\"", name)),
+            name
+        )
+    }
+
+    /// Whether `define_function` needs the functions exported as `BASH_FUNC_name%%`.
+    fn needs_exported_functions(&self) -> bool {
+        true
+    }
+
+    fn run_shell_hook(&self) -> String {
+        "eval \"$shellHook\"\n".to_string()
+    }
+
+    fn set_prompt(&self, prefix: &str) -> String {
+        format!("PS1={}\"$PS1\"\n", quote(prefix))
+    }
+}
+
+struct Bash;
+struct Zsh;
+struct Ksh;
+struct Fish;
+struct Nushell;
+
+/// The backend for `shell`, a name or path of the executable.
+pub fn backend(shell: &str) -> Result<Box<dyn ShellBackend>, Error> {
+    match shell_name(shell) {
+        "bash" => Ok(Box::new(Bash)),
+        "zsh" => Ok(Box::new(Zsh)),
+        "ksh" => Ok(Box::new(Ksh)),
+        "fish" => Ok(Box::new(Fish)),
+        "nu" | "nushell" => Ok(Box::new(Nushell)),
+        name => Err(anyhow!(
+            "unsupported shell: {}, supported shells are {}",
+            name,
+            SUPPORTED_SHELLS.join(", ")
+        )),
+    }
+}
+
+impl ShellBackend for Bash {
+    fn add_init_script(
+        &self,
+        command: &mut Command,
+        script: &str,
+        user_config: bool,
+    ) -> Result<(), Error> {
+        let rcfile = keep_temp_file(".bashrc", |file| {
+            let mut rcfile = format!("rm -f {}\n", quote(&file.to_string_lossy()));
+            if user_config {
+                rcfile += "[ -e ~/.bashrc ] && source ~/.bashrc\n";
+            }
+            rcfile + script
+        })?;
+        command.arg("--rcfile").arg(rcfile);
+
+        Ok(())
+    }
+
+    fn set_array(&self, name: &str, values: &[String]) -> Option<String> {
+        let values: Vec<String> = values.iter().map(|v| quote(v)).collect();
+        Some(format!("declare -a {}=({})\n", name, values.join(" ")))
+    }
+
+    fn set_associative_array(&self, name: &str, values: &[(&String, &String)]) -> Option<String> {
+        let values: Vec<String> = values
+            .iter()
+            .map(|(k, v)| format!("[{}]={}", quote(k), quote(v)))
+            .collect();
+        Some(format!("declare -A {}=({})\n", name, values.join(" ")))
+    }
+
+    fn define_function(&self, name: &str, body: &str) -> String {
+        format!("{} ()\n{{\n{}\n}}\n", name, body)
+    }
+
+    fn needs_exported_functions(&self) -> bool {
+        false
+    }
+}
+
+impl ShellBackend for Zsh {
+    /// zsh reads its startup files from ZDOTDIR, which points to a directory with a .zshenv
+    /// and .zshrc that source the user's own files.
+    fn add_init_script(
+        &self,
+        command: &mut Command,
+        script: &str,
+        user_config: bool,
+    ) -> Result<(), Error> {
+        let dir = tempfile::Builder::new()
+            .prefix("nix-dev-env-")
+            .tempdir()
+            .context("failed to create the zsh startup files")?
+            .into_path();
+        let user_zdotdir = env::var("ZDOTDIR").ok();
+        let (zshenv, zshrc) = zsh_startup_files(&dir, user_zdotdir.as_deref(), script, user_config);
+        fs::write(dir.join(".zshenv"), zshenv)
+            .and_then(|_| fs::write(dir.join(".zshrc"), zshrc))
+            .context("failed to write the zsh startup files")?;
+
+        command.env("ZDOTDIR", dir);
+
+        Ok(())
+    }
+
+    /// zsh doesn't know the [key]=value syntax before 5.5.
+    fn set_associative_array(&self, name: &str, values: &[(&String, &String)]) -> Option<String> {
+        let values: Vec<String> = values
+            .iter()
+            .map(|(k, v)| format!("{} {}", quote(k), quote(v)))
+            .collect();
+        Some(format!(
+            "typeset -A {}\n{}=({})\n",
+            name,
+            name,
+            values.join(" ")
+        ))
+    }
+}
+
+/// .zshenv and .zshrc for a ZDOTDIR that source the user's files, the .zshrc runs `script`.
+/// `user_zdotdir` is the ZDOTDIR of the user, if they set one.
+fn zsh_startup_files(
+    dir: &Path,
+    user_zdotdir: Option<&str>,
+    script: &str,
+    user_config: bool,
+) -> (String, String) {
+    let restore = match user_zdotdir {
+        Some(zdotdir) => format!("ZDOTDIR={}\n", quote(zdotdir)),
+        None => "unset ZDOTDIR\n".to_string(),
+    };
+
+    // the user's .zshenv may change ZDOTDIR, zsh still has to find our .zshrc
+    let mut zshenv = restore;
+    if user_config {
+        zshenv += "[ -e \"${ZDOTDIR:-$HOME}/.zshenv\" ] && source \"${ZDOTDIR:-$HOME}/.zshenv\"\n";
+    }
+    zshenv += "_nix_dev_env_zdotdir=\"${ZDOTDIR-}\"\n";
+    zshenv += &format!("ZDOTDIR={}\n", quote(&dir.to_string_lossy()));
+
+    let mut zshrc = format!("rm -rf {}\n", quote(&dir.to_string_lossy()));
+    zshrc += "ZDOTDIR=\"$_nix_dev_env_zdotdir\"\n";
+    zshrc += "unset _nix_dev_env_zdotdir\n";
+    zshrc += "[ -z \"$ZDOTDIR\" ] && unset ZDOTDIR\n";
+    if user_config {
+        zshrc += "[ -e \"${ZDOTDIR:-$HOME}/.zshrc\" ] && source \"${ZDOTDIR:-$HOME}/.zshrc\"\n";
+    }
+    zshrc += script;
+
+    (zshenv, zshrc)
+}
+
+impl ShellBackend for Ksh {
+    /// ksh runs the file in ENV, which sources the user's ENV or ~/.kshrc.
+    fn add_init_script(
+        &self,
+        command: &mut Command,
+        script: &str,
+        user_config: bool,
+    ) -> Result<(), Error> {
+        let user_env = env::var("ENV").ok();
+        let file = keep_temp_file(".kshrc", |file| {
+            let mut env_file = format!("rm -f {}\n", quote(&file.to_string_lossy()));
+            env_file += &match &user_env {
+                Some(user_env) => format!("ENV={}\n", quote(user_env)),
+                None => "unset ENV\n".to_string(),
+            };
+            if user_config {
+                env_file += "[ -e \"${ENV:-$HOME/.kshrc}\" ] && . \"${ENV:-$HOME/.kshrc}\"\n";
+            }
+            env_file + script
+        })?;
+        command.env("ENV", file);
+
+        Ok(())
+    }
+}
+
+impl ShellBackend for Fish {
+    /// fish runs --init-command after its own config files.
+    fn add_init_script(
+        &self,
+        command: &mut Command,
+        script: &str,
+        user_config: bool,
+    ) -> Result<(), Error> {
+        let file = keep_temp_file(".fish", |file| {
+            format!("rm -f {}\n{}", fish_quote(&file.to_string_lossy()), script)
+        })?;
+        if !user_config {
+            command.arg("--no-config");
+        }
+        command
+            .arg("--init-command")
+            .arg(format!("source {}", fish_quote(&file.to_string_lossy())));
+
+        Ok(())
+    }
+
+    fn set_variable(&self, name: &str, value: &str) -> String {
+        format!("set -gx {} {}\n", name, fish_quote(value))
+    }
+
+    /// `:` separated variables become path variables, which fish keeps as lists.
+    fn prepend_path(&self, name: &str, path: &str, separator: &str) -> String {
+        if separator == ":" {
+            return format!(
+                "set -l _nix_dev_env_paths (string split -n : -- \"${name}\"); \
+                 while set -l i (contains -i -- {path} $_nix_dev_env_paths); set -e _nix_dev_env_paths[$i]; end; \
+                 set -gx --path {name} {path} $_nix_dev_env_paths; set -e _nix_dev_env_paths\n",
+                name = name,
+                path = fish_quote(path)
+            );
+        }
+
+        let value = format!(
+            "{name} (string join -- {sep} {path} (string split -n -- {sep} \"${name}\"))",
+            name = name,
+            sep = fish_quote(separator),
+            path = fish_quote(path)
+        );
+        fish_add_path(name, path, separator, &value)
+    }
+
+    fn append_path(&self, name: &str, path: &str, separator: &str) -> String {
+        let value = if separator == ":" {
+            format!(
+                "--path {name} (string split -n : -- \"${name}\") {path}",
+                name = name,
+                path = fish_quote(path)
+            )
         } else {
+            format!(
+                "{name} (string join -- {sep} (string split -n -- {sep} \"${name}\") {path})",
+                name = name,
+                sep = fish_quote(separator),
+                path = fish_quote(path)
+            )
+        };
+        fish_add_path(name, path, separator, &value)
+    }
+
+    fn set_path(&self, name: &str, paths: &[&str], separator: &str) -> String {
+        if separator == ":" {
+            let paths: Vec<String> = paths.iter().map(|p| fish_quote(p)).collect();
+            format!("set -gx --path {} {}\n", name, paths.join(" "))
+        } else {
+            self.set_variable(name, &paths.join(separator))
+        }
+    }
+
+    fn set_array(&self, name: &str, values: &[String]) -> Option<String> {
+        let values: Vec<String> = values.iter().map(|v| fish_quote(v)).collect();
+        Some(format!("set -g {} {}\n", name, values.join(" ")))
+    }
+
+    fn set_associative_array(&self, _: &str, _: &[(&String, &String)]) -> Option<String> {
+        None
+    }
+
+    fn define_function(&self, name: &str, _body: &str) -> String {
+        format!(
+            "function {}\n    bash -c {} {} $argv\nend\n",
+            name,
+            fish_quote(&format!("{} \"$@\"", name)),
+            name
+        )
+    }
+
+    /// Runs the shellHook in bash, changes to the environment are lost.
+    fn run_shell_hook(&self) -> String {
+        "bash -c \"$shellHook\"\n".to_string()
+    }
+
+    fn set_prompt(&self, prefix: &str) -> String {
+        format!(
+            "functions -c fish_prompt _nix_dev_env_fish_prompt\nfunction fish_prompt\n    printf '%s' {}\n    _nix_dev_env_fish_prompt\nend\n",
+            fish_quote(prefix)
+        )
+    }
+}
+
+/// Sets `name` to `value` unless `path` is already in the list.
+/// For lists that aren't `:` separated, `path` is the whole dev value.
+fn fish_add_path(name: &str, path: &str, separator: &str, value: &str) -> String {
+    if separator == ":" {
+        format!(
+            "contains -- {path} (string split -- : \"${name}\"); or set -gx {value}\n",
+            name = name,
+            path = fish_quote(path),
+            value = value
+        )
+    } else {
+        let pattern = fish_quote(&format!("{0}{1}{0}", separator, path));
+        format!(
+            "string replace -q -- {pattern} '' \"{sep}${name}{sep}\"; or set -gx {value}\n",
+            pattern = pattern,
+            sep = escape_fish_double_quoted(separator),
+            name = name,
+            value = value
+        )
+    }
+}
+
+/// Adds a path to a variable that is either a list, like PATH, or a string.
+/// Prepending moves a path that is already in there to the front.
+/// For strings that aren't `:` separated, `path` is the whole dev value.
+const NU_ADD_PATH: &str = r#"def _nix_dev_env_add_path [current, path: string, separator: string, --append] {
+    let is_string = ($current | describe) == "string"
+    if $is_string and $separator != ":" {
+        if ($"($separator)($current)($separator)" | str contains $"($separator)($path)($separator)") { return $current }
+        let values = if $append { [$current $path] } else { [$path $current] }
+        return ($values | where $it != "" | str join $separator)
+    }
+    let paths = if $is_string { $current | split row $separator | where $it != "" } else { $current }
+    let paths = if not $append { $paths | where $it != $path | prepend $path } else if $path in $paths { $paths } else { $paths | append $path }
+    if $is_string { $paths | str join $separator } else { $paths }
+}
+"#;
+
+impl ShellBackend for Nushell {
+    /// nu runs --execute after its own config files.
+    fn add_init_script(
+        &self,
+        command: &mut Command,
+        script: &str,
+        user_config: bool,
+    ) -> Result<(), Error> {
+        let file = keep_temp_file(".nu", |file| {
+            format!(
+                "rm -f {}\n{}{}",
+                nu_quote(&file.to_string_lossy()),
+                NU_ADD_PATH,
+                script
+            )
+        })?;
+        if !user_config {
+            command.arg("--no-config-file");
+        }
+        command
+            .arg("--execute")
+            .arg(format!("source {}", nu_quote(&file.to_string_lossy())));
+
+        Ok(())
+    }
+
+    fn set_variable(&self, name: &str, value: &str) -> String {
+        format!("$env.{} = {}\n", name, nu_quote(value))
+    }
+
+    fn prepend_path(&self, name: &str, path: &str, separator: &str) -> String {
+        format!(
+            "$env.{name} = (_nix_dev_env_add_path ($env.{name}? | default \"\") {path} {sep})\n",
+            name = name,
+            path = nu_quote(path),
+            sep = nu_quote(separator)
+        )
+    }
+
+    fn append_path(&self, name: &str, path: &str, separator: &str) -> String {
+        format!(
+            "$env.{name} = (_nix_dev_env_add_path --append ($env.{name}? | default \"\") {path} {sep})\n",
+            name = name,
+            path = nu_quote(path),
+            sep = nu_quote(separator)
+        )
+    }
+
+    /// Keeps a list a list, like PATH, and joins the paths otherwise.
+    fn set_path(&self, name: &str, paths: &[&str], separator: &str) -> String {
+        let paths: Vec<String> = paths.iter().map(|p| nu_quote(p)).collect();
+        format!(
+            "$env.{name} = ([{paths}] | if ($env.{name}? | describe) starts-with \"list\" {{ $in }} else {{ str join {sep} }})\n",
+            name = name,
+            paths = paths.join(" "),
+            sep = nu_quote(separator)
+        )
+    }
+
+    fn set_array(&self, name: &str, values: &[String]) -> Option<String> {
+        let values: Vec<String> = values.iter().map(|v| nu_quote(v)).collect();
+        Some(format!("$env.{} = [{}]\n", name, values.join(" ")))
+    }
+
+    fn set_associative_array(&self, name: &str, values: &[(&String, &String)]) -> Option<String> {
+        let values: Vec<String> = values
+            .iter()
+            .map(|(k, v)| format!("{}: {}", nu_quote(k), nu_quote(v)))
+            .collect();
+        Some(format!("$env.{} = {{{}}}\n", name, values.join(", ")))
+    }
+
+    fn define_function(&self, name: &str, _body: &str) -> String {
+        format!(
+            "def --wrapped {} [...args] {{ ^bash -c {} {} ...$args }}\n",
+            name,
+            nu_quote(&format!("{} \"$@\"", name)),
+            name
+        )
+    }
+
+    /// Runs the shellHook in bash, changes to the environment are lost.
+    fn run_shell_hook(&self) -> String {
+        "^bash -c $env.shellHook\n".to_string()
+    }
+
+    fn set_prompt(&self, prefix: &str) -> String {
+        format!(
+            "let _nix_dev_env_prompt = $env.PROMPT_COMMAND?\n$env.PROMPT_COMMAND = {{|| {} + (if ($_nix_dev_env_prompt | describe) starts-with \"closure\" {{ do $_nix_dev_env_prompt }} else {{ $_nix_dev_env_prompt | default \"\" }}) }}\n",
+            nu_quote(prefix)
+        )
+    }
+}
+
+/// Script that sets up the dev env after the user's startup files ran,
+/// so they can't override it.
+fn init_script(backend: &dyn ShellBackend, env: &FinalEnv, options: &ShellOptions) -> String {
+    let mut script = String::new();
+
+    let mut variables: Vec<_> = env
+        .variables
+        .iter()
+        .filter(|(name, _)| !IGNORED_VARS.contains(&name.as_str()))
+        .collect();
+    variables.sort();
+    for (name, value) in variables {
+        script += &backend.set_variable(name, value);
+    }
+
+    let mut paths: Vec<_> = env.paths.iter().collect();
+    paths.sort();
+    for (name, value) in paths {
+        let separator = env.separator(name);
+        let dev_paths: Vec<&str> = value.split(separator).filter(|p| !p.is_empty()).collect();
+        // flags can repeat, so they are added as a whole instead of one by one
+        let added = match separator {
+            ":" => dev_paths.clone(),
+            _ if value.is_empty() => vec![],
+            _ => vec![value.as_str()],
+        };
+
+        match options
+            .path_precedence
+            .get(name)
+            .copied()
+            .unwrap_or_default()
+        {
+            // prepended one by one, the first path has to end up first
+            PathPrecedence::Prepend => {
+                for path in added.iter().rev() {
+                    script += &backend.prepend_path(name, path, separator);
+                }
+            }
+            PathPrecedence::Append => {
+                for path in added {
+                    script += &backend.append_path(name, path, separator);
+                }
+            }
+            PathPrecedence::Replace => script += &backend.set_path(name, &dev_paths, separator),
+        }
+    }
+
+    let mut arrays: Vec<_> = env.arrays.iter().collect();
+    arrays.sort();
+    for (name, values) in arrays {
+        match backend.set_array(name, values) {
+            Some(s) => script += &s,
+            None => eprintln!("warning: the shell has no arrays, skipping {}", name),
+        }
+    }
+
+    let mut associative_arrays: Vec<_> = env.associative_arrays.iter().collect();
+    associative_arrays.sort_by_key(|(name, _)| *name);
+    for (name, values) in associative_arrays {
+        let mut values: Vec<_> = values.iter().collect();
+        values.sort();
+        match backend.set_associative_array(name, &values) {
+            Some(s) => script += &s,
+            None => eprintln!(
+                "warning: the shell has no associative arrays, skipping {}",
+                name
+            ),
+        }
+    }
+
+    let mut functions: Vec<_> = env.bash_functions.iter().collect();
+    functions.sort();
+    for (name, body) in functions {
+        script += &backend.define_function(name, body);
+    }
+
+    if let Some(prefix) = &options.prompt {
+        script += &backend.set_prompt(prefix);
+    }
+
+    if options.shell_hook && env.variables.contains_key("shellHook") {
+        script += &backend.run_shell_hook();
+    }
+
+    script
+}
+
+/// Creates a file that outlives this process, the shell replaces it and has to remove the file.
+fn keep_temp_file(suffix: &str, contents: impl FnOnce(&Path) -> String) -> Result<PathBuf, Error> {
+    let (mut file, path) = tempfile::Builder::new()
+        .prefix("nix-dev-env-")
+        .suffix(suffix)
+        .tempfile()
+        .context("failed to create the shell startup file")?
+        .keep()
+        .context("failed to keep the shell startup file")?;
+    file.write_all(contents(&path).as_bytes())
+        .context("failed to write the shell startup file")?;
+
+    Ok(path)
+}
+
+/// Command that runs `program` with the variables and paths of the dev env.
+pub fn env_command<S: AsRef<OsStr>>(env: &FinalEnv, program: S, options: &ShellOptions) -> Command {
+    let mut command = Command::new(program);
+
+    if options.pure {
+        command.env_clear();
+        for k in PURE_KEEP
+            .iter()
+            .copied()
+            .chain(options.keep.iter().map(String::as_str))
+        {
+            if let Some(v) = env::var_os(k) {
+                command.env(k, v);
+            }
+        }
+    }
+
+    for (k, v) in &env.variables {
+        if !IGNORED_VARS.contains(&k.as_str()) {
             command.env(k, v);
         }
     }
 
+    for (k, v) in &env.paths {
+        let precedence = match options.pure {
+            true => PathPrecedence::Replace,
+            false => options.path_precedence.get(k).copied().unwrap_or_default(),
+        };
+        let inherited = env::var(k).unwrap_or_default();
+        command.env(k, merge_paths(&inherited, v, env.separator(k), precedence));
+    }
+
+    if options.export_functions {
+        export_functions(&mut command, env);
+    }
+
+    command
+}
+
+/// Exports the functions as `BASH_FUNC_name%%`, so bash started by `command` inherits them.
+fn export_functions(command: &mut Command, env: &FinalEnv) {
+    for (name, body) in &env.bash_functions {
+        command.env(
+            format!("BASH_FUNC_{}%%", name),
+            format!("() {{\n{}\n}}", body),
+        );
+    }
+}
+
+/// Looks up `program` on the inherited PATH, unless it's already a path.
+fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(PathBuf::from(program));
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| {
+            fs::metadata(path)
+                .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+}
+
+/// Command that starts `shell` with the dev env, which is set in the environment and again
+/// in an init script after the user's startup files. The init script also defines the arrays
+/// and functions, changes the prompt and runs the shellHook.
+pub fn shell_command(
+    env: &FinalEnv,
+    shell: &str,
+    options: &ShellOptions,
+) -> Result<Command, Error> {
+    let backend = backend(shell)?;
+    // the environment of a pure shell only has the dev PATH, which won't contain the shell
+    let program = match options.pure {
+        true => find_program(shell).with_context(|| format!("{} not found on PATH", shell))?,
+        false => PathBuf::from(shell),
+    };
+    let mut command = env_command(env, program, options);
+    if !options.export_functions && backend.needs_exported_functions() {
+        export_functions(&mut command, env);
+    }
+
+    backend.add_init_script(
+        &mut command,
+        &init_script(backend.as_ref(), env, options),
+        !options.pure,
+    )?;
+
+    Ok(command)
+}
+
+/// Runs `command` to completion and returns its exit code, or 128 + the signal that killed it.
+/// SIGTERM and SIGHUP are forwarded to it. SIGINT and SIGQUIT from the terminal already reach
+/// it through the process group, they only don't kill this process anymore.
+pub fn run_forwarding_signals(command: &mut Command) -> Result<i32, Error> {
+    let mut signals = Signals::new([SIGINT, SIGQUIT, SIGTERM, SIGHUP])
+        .context("failed to register signal handler")?;
+    let handle = signals.handle();
+
+    let mut child = command.spawn()?;
+    let pid = libc::pid_t::try_from(child.id()).context("invalid pid")?;
+
+    let forward = thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGTERM || signal == SIGHUP {
+                // SAFETY: kill has no memory safety requirements, the pid is our unreaped child
+                unsafe { libc::kill(pid, signal) };
+            }
+        }
+    });
+
+    let status = child.wait();
+    handle.close();
+    forward
+        .join()
+        .map_err(|_| anyhow!("signal forwarding thread panicked"))?;
+    let status = status?;
+
+    Ok(status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)))
+}
+
+pub fn start_shell(
+    env: &FinalEnv,
+    shell: &String,
+    only_print: bool,
+    options: &ShellOptions,
+) -> Result<(), Error> {
     if only_print {
         let stdout = stdout();
         let mut stdout = stdout.lock();
@@ -46,7 +917,218 @@ pub fn start_shell(env: &FinalEnv, shell: &String, only_print: bool) -> Result<(
 
         Ok(())
     } else {
+        let mut command = shell_command(env, shell, options)?;
         println!("starting shell: {}", shell);
         Err(command.exec().into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env() -> FinalEnv {
+        FinalEnv {
+            paths: HashMap::from([
+                ("PATH".to_string(), "/dev/bin:/dev/sbin".to_string()),
+                (
+                    "NIX_CFLAGS_COMPILE".to_string(),
+                    "-I/dev/include".to_string(),
+                ),
+            ]),
+            variables: HashMap::from([("shellHook".to_string(), "echo hi".to_string())]),
+            arrays: HashMap::from([(
+                "buildInputs".to_string(),
+                vec!["/nix/store/a b".to_string(), "it's".to_string()],
+            )]),
+            associative_arrays: HashMap::from([(
+                "outputs".to_string(),
+                HashMap::from([
+                    ("out".to_string(), "/nix/store/out".to_string()),
+                    ("dev".to_string(), "/nix/store/dev".to_string()),
+                ]),
+            )]),
+            bash_functions: HashMap::from([(
+                "runHook".to_string(),
+                "    local hookName=\"$1\";\n    echo \"$hookName\"".to_string(),
+            )]),
+            separators: HashMap::from([("NIX_CFLAGS_COMPILE".to_string(), " ".to_string())]),
+        }
+    }
+
+    fn options() -> ShellOptions {
+        ShellOptions {
+            export_functions: false,
+            shell_hook: true,
+            prompt: Some("(dev) ".to_string()),
+            ..ShellOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_bash_init_script() {
+        assert_eq!(
+            init_script(&Bash, &env(), &options()),
+            "export shellHook='echo hi'\n\
+             case \" ${NIX_CFLAGS_COMPILE-} \" in *' -I/dev/include '*) ;; *) export NIX_CFLAGS_COMPILE='-I/dev/include'\"${NIX_CFLAGS_COMPILE:+ $NIX_CFLAGS_COMPILE}\" ;; esac\n\
+             _nix_dev_env_paths=\":${PATH-}:\"; \
+             while case \"$_nix_dev_env_paths\" in *':/dev/sbin:'*) ;; *) false ;; esac; do \
+             _nix_dev_env_paths=${_nix_dev_env_paths%%':/dev/sbin:'*}:${_nix_dev_env_paths#*':/dev/sbin:'}; done; \
+             _nix_dev_env_paths=${_nix_dev_env_paths#:}; _nix_dev_env_paths=${_nix_dev_env_paths%:}; \
+             export PATH='/dev/sbin'\"${_nix_dev_env_paths:+:$_nix_dev_env_paths}\"; unset _nix_dev_env_paths\n\
+             _nix_dev_env_paths=\":${PATH-}:\"; \
+             while case \"$_nix_dev_env_paths\" in *':/dev/bin:'*) ;; *) false ;; esac; do \
+             _nix_dev_env_paths=${_nix_dev_env_paths%%':/dev/bin:'*}:${_nix_dev_env_paths#*':/dev/bin:'}; done; \
+             _nix_dev_env_paths=${_nix_dev_env_paths#:}; _nix_dev_env_paths=${_nix_dev_env_paths%:}; \
+             export PATH='/dev/bin'\"${_nix_dev_env_paths:+:$_nix_dev_env_paths}\"; unset _nix_dev_env_paths\n\
+             declare -a buildInputs=('/nix/store/a b' 'it'\\''s')\n\
+             declare -A outputs=(['dev']='/nix/store/dev' ['out']='/nix/store/out')\n\
+             runHook ()\n{\n    local hookName=\"$1\";\n    echo \"$hookName\"\n}\n\
+             PS1='(dev) '\"$PS1\"\n\
+             eval \"$shellHook\"\n"
+        );
+    }
+
+    #[test]
+    fn test_zsh_init_script() {
+        let script = init_script(&Zsh, &env(), &options());
+
+        assert!(script.contains(
+            "typeset -A outputs\noutputs=('dev' '/nix/store/dev' 'out' '/nix/store/out')\n"
+        ));
+        assert!(script.contains("runHook () { bash -c 'runHook \"$@\"' runHook \"$@\"; }\n"));
+    }
+
+    #[test]
+    fn test_fish_init_script() {
+        assert_eq!(
+            init_script(&Fish, &env(), &options()),
+            "set -gx shellHook 'echo hi'\n\
+             string replace -q -- ' -I/dev/include ' '' \" $NIX_CFLAGS_COMPILE \"; or set -gx NIX_CFLAGS_COMPILE (string join -- ' ' '-I/dev/include' (string split -n -- ' ' \"$NIX_CFLAGS_COMPILE\"))\n\
+             set -l _nix_dev_env_paths (string split -n : -- \"$PATH\"); \
+             while set -l i (contains -i -- '/dev/sbin' $_nix_dev_env_paths); set -e _nix_dev_env_paths[$i]; end; \
+             set -gx --path PATH '/dev/sbin' $_nix_dev_env_paths; set -e _nix_dev_env_paths\n\
+             set -l _nix_dev_env_paths (string split -n : -- \"$PATH\"); \
+             while set -l i (contains -i -- '/dev/bin' $_nix_dev_env_paths); set -e _nix_dev_env_paths[$i]; end; \
+             set -gx --path PATH '/dev/bin' $_nix_dev_env_paths; set -e _nix_dev_env_paths\n\
+             set -g buildInputs '/nix/store/a b' 'it\\'s'\n\
+             function runHook\n    bash -c 'runHook \"$@\"' runHook $argv\nend\n\
+             functions -c fish_prompt _nix_dev_env_fish_prompt\n\
+             function fish_prompt\n    printf '%s' '(dev) '\n    _nix_dev_env_fish_prompt\nend\n\
+             bash -c \"$shellHook\"\n"
+        );
+    }
+
+    #[test]
+    fn test_nushell_init_script() {
+        let script = init_script(&Nushell, &env(), &options());
+
+        assert!(script.contains("$env.buildInputs = [\"/nix/store/a b\" \"it's\"]\n"));
+        assert!(script.contains(
+            "$env.outputs = {\"dev\": \"/nix/store/dev\", \"out\": \"/nix/store/out\"}\n"
+        ));
+        assert!(script.contains(
+            "$env.PATH = (_nix_dev_env_add_path ($env.PATH? | default \"\") \"/dev/bin\" \":\")\n"
+        ));
+        assert!(script.ends_with("^bash -c $env.shellHook\n"));
+    }
+
+    #[test]
+    fn test_ignored_vars() {
+        let mut env = env();
+        env.variables.extend([
+            ("BASHOPTS".to_string(), "checkwinsize".to_string()),
+            ("HOME".to_string(), "/homeless-shelter".to_string()),
+            ("UID".to_string(), "1000".to_string()),
+        ]);
+
+        let script = init_script(&Bash, &env, &options());
+        assert!(script.starts_with("export shellHook='echo hi'\n"));
+        assert!(
+            !script.contains("BASHOPTS") && !script.contains("HOME") && !script.contains("UID")
+        );
+
+        let command = env_command(&env, "bash", &options());
+        let names: Vec<_> = command.get_envs().map(|(k, _)| k).collect();
+        assert!(names.contains(&OsStr::new("shellHook")));
+        assert!(!names.contains(&OsStr::new("BASHOPTS")));
+        assert!(!names.contains(&OsStr::new("HOME")));
+        assert!(!names.contains(&OsStr::new("UID")));
+    }
+
+    #[test]
+    fn test_merge_paths() {
+        let inherited = "/usr/bin:/dev/bin:/bin";
+        let dev = "/dev/bin:/dev/sbin";
+
+        assert_eq!(
+            merge_paths(inherited, dev, ":", PathPrecedence::Prepend),
+            "/dev/bin:/dev/sbin:/usr/bin:/bin"
+        );
+        assert_eq!(
+            merge_paths(inherited, dev, ":", PathPrecedence::Append),
+            "/usr/bin:/dev/bin:/bin:/dev/sbin"
+        );
+        assert_eq!(
+            merge_paths(inherited, dev, ":", PathPrecedence::Replace),
+            "/dev/bin:/dev/sbin"
+        );
+        assert_eq!(merge_paths("", dev, ":", PathPrecedence::Append), dev);
+        assert_eq!(
+            merge_paths("-O2", "-I/dev/include", " ", PathPrecedence::Prepend),
+            "-I/dev/include -O2"
+        );
+        assert_eq!(
+            merge_paths(
+                "-O2 -isystem /a/include -isystem /b/include",
+                "-isystem /a/include -isystem /b/include",
+                " ",
+                PathPrecedence::Prepend
+            ),
+            "-O2 -isystem /a/include -isystem /b/include",
+            "the whole dev value is already there"
+        );
+        assert_eq!(
+            merge_paths(
+                "-isystem /a/include",
+                "-isystem /a/include -isystem /b/include",
+                " ",
+                PathPrecedence::Append
+            ),
+            "-isystem /a/include -isystem /a/include -isystem /b/include"
+        );
+    }
+
+    #[test]
+    fn test_append_path() {
+        let options = ShellOptions {
+            path_precedence: HashMap::from([("PATH".to_string(), PathPrecedence::Append)]),
+            ..ShellOptions::default()
+        };
+        let env = FinalEnv {
+            paths: HashMap::from([("PATH".to_string(), "/dev/bin:/dev/sbin".to_string())]),
+            ..env()
+        };
+
+        assert!(init_script(&Bash, &env, &options).starts_with(
+            "export shellHook='echo hi'\n\
+             case \":${PATH-}:\" in *':/dev/bin:'*) ;; *) export PATH=\"${PATH:+$PATH:}\"'/dev/bin' ;; esac\n\
+             case \":${PATH-}:\" in *':/dev/sbin:'*) ;; *) export PATH=\"${PATH:+$PATH:}\"'/dev/sbin' ;; esac\n"
+        ));
+    }
+
+    #[test]
+    fn test_backend() {
+        assert!(backend("/run/current-system/sw/bin/fish").is_ok());
+        assert!(backend("nu").is_ok());
+
+        let message = backend("tcsh")
+            .err()
+            .expect("tcsh is supported")
+            .to_string();
+        assert_eq!(
+            message,
+            "unsupported shell: tcsh, supported shells are bash, zsh, ksh, fish, nu"
+        );
+    }
+}
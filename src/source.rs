@@ -0,0 +1,68 @@
+use crate::nix::{read_dev_env, Env};
+use anyhow::{Error, Result};
+use std::path::PathBuf;
+
+/// Something that provides a dev env in the `nix print-dev-env --json` format.
+pub trait EnvSource {
+    fn get_env(&self) -> Result<Env, Error>;
+}
+
+/// Reads a pre-recorded env from a file, `-` reads from stdin.
+pub struct FileSource {
+    file: PathBuf,
+}
+
+impl FileSource {
+    pub fn new(file: PathBuf) -> FileSource {
+        FileSource { file }
+    }
+}
+
+impl EnvSource for FileSource {
+    fn get_env(&self) -> Result<Env, Error> {
+        read_dev_env(&self.file)
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::EnvSource;
+    use crate::nix::Env;
+    use anyhow::{anyhow, Error, Result};
+    use std::{
+        cell::{Cell, RefCell},
+        collections::VecDeque,
+    };
+
+    /// Returns scripted responses in order, either a json document or an error message.
+    pub struct FakeSource {
+        responses: RefCell<VecDeque<Result<String, String>>>,
+        pub calls: Cell<usize>,
+    }
+
+    impl FakeSource {
+        pub fn new(responses: Vec<Result<&str, &str>>) -> FakeSource {
+            FakeSource {
+                responses: RefCell::new(
+                    responses
+                        .into_iter()
+                        .map(|r| r.map(str::to_string).map_err(str::to_string))
+                        .collect(),
+                ),
+                calls: Cell::new(0),
+            }
+        }
+    }
+
+    impl EnvSource for FakeSource {
+        fn get_env(&self) -> Result<Env, Error> {
+            self.calls.set(self.calls.get() + 1);
+
+            match self.responses.borrow_mut().pop_front() {
+                Some(Ok(json)) => Ok(serde_json::from_str(&json)?),
+                Some(Err(e)) => Err(anyhow!("{}", e)),
+                None => Err(anyhow!("fake source has no responses left")),
+            }
+        }
+    }
+}
@@ -0,0 +1,1365 @@
+use std::{
+    env, fs,
+    io::Write,
+    os::unix::{
+        fs::PermissionsExt,
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    thread,
+    time::Duration,
+};
+
+use tempfile::TempDir;
+
+/// Temporary directory with stub executables that shadow the real ones on PATH,
+/// and isolated cache and data dirs.
+struct Sandbox {
+    dir: TempDir,
+    system_path: bool,
+}
+
+impl Sandbox {
+    fn new() -> Sandbox {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("bin")).unwrap();
+        Sandbox {
+            dir,
+            system_path: true,
+        }
+    }
+
+    /// Sandbox whose PATH only contains the stubs.
+    fn isolated() -> Sandbox {
+        Sandbox {
+            system_path: false,
+            ..Sandbox::new()
+        }
+    }
+
+    fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Writes an executable `bin/<name>` running the given sh script.
+    fn stub(&self, name: &str, script: &str) {
+        let file = self.path().join("bin").join(name);
+        fs::write(&file, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Stub `nix` that answers `--version` and `show-config` from the `nix-version` and
+    /// `nix-features` files in the sandbox, and runs the script for anything else.
+    fn stub_nix(&self, script: &str) {
+        let prelude = r#"
+case "$*" in
+    --version)
+        cat SANDBOX/nix-version 2>/dev/null || echo 'nix (Nix) 2.18.1'
+        exit 0;;
+    *show-config*)
+        features=$(cat SANDBOX/nix-features 2>/dev/null || echo '"nix-command", "flakes"')
+        echo "{ \"experimental-features\": { \"value\": [$features] }, \"system\": { \"value\": \"x86_64-linux\" } }"
+        exit 0;;
+esac
+"#
+        .replace("SANDBOX", &self.path().display().to_string());
+
+        self.stub("nix", &format!("{}\n{}", prelude, script));
+    }
+
+    /// Stub `nix` that prints the given json document.
+    fn stub_nix_json(&self, json: &str) {
+        self.stub_nix(&format!("cat <<'EOF'\n{}\nEOF", json));
+    }
+
+    /// Stub `nix` that records its arguments in `nix-args` and prints the given json document.
+    fn stub_nix_json_args(&self, json: &str) {
+        self.stub_nix(&format!(
+            "echo \"$@\" > {}\ncat <<'EOF'\n{}\nEOF",
+            self.path().join("nix-args").display(),
+            json
+        ));
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.run_in(self.path(), args)
+    }
+
+    fn run_in(&self, dir: &Path, args: &[&str]) -> Output {
+        self.run_with_input(dir, args, "")
+    }
+
+    fn run_with_input(&self, dir: &Path, args: &[&str], input: &str) -> Output {
+        let mut child = self.spawn(dir, args);
+
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+
+        child.wait_with_output().unwrap()
+    }
+
+    fn run_with_env(&self, args: &[&str], vars: &[(&str, &str)]) -> Output {
+        self.command(self.path(), args)
+            .envs(vars.iter().copied())
+            .stdin(Stdio::null())
+            .output()
+            .unwrap()
+    }
+
+    /// Starts nix-dev-env with piped stdin, stdout and stderr.
+    fn spawn(&self, dir: &Path, args: &[&str]) -> Child {
+        self.command(dir, args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    fn command(&self, dir: &Path, args: &[&str]) -> Command {
+        let system_path = if self.system_path {
+            env::var_os("PATH").unwrap_or_default()
+        } else {
+            Default::default()
+        };
+        let path = env::join_paths(
+            [self.path().join("bin")]
+                .into_iter()
+                .chain(env::split_paths(&system_path)),
+        )
+        .unwrap();
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_nix-dev-env"));
+        command
+            .args(args)
+            .current_dir(dir)
+            .env("PATH", path)
+            .env("HOME", self.path())
+            .env("XDG_CACHE_HOME", self.path().join("cache"))
+            .env("XDG_DATA_HOME", self.path().join("data"));
+        command
+    }
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+const ENV_JSON: &str = r#"
+{
+    "bashFunctions": { "func1": "body1" },
+    "variables": {
+        "PATH": { "type": "exported", "value": "/dev/bin" },
+        "var1": { "type": "exported", "value": "value1" }
+    }
+}
+"#;
+
+#[test]
+fn test_print_env() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(ENV_JSON);
+
+    let output = sandbox.run(&["--print"]);
+
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(stdout(&output).contains("PATH = \"/dev/bin\""));
+    assert!(stdout(&output).contains("var1 = \"value1\""));
+}
+
+#[test]
+fn test_nix_failure() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix("echo 'error: evaluation aborted' >&2\nexit 1");
+
+    let output = sandbox.run(&["--print"]);
+
+    assert_eq!(output.status.code(), Some(6), "nix failure was ignored");
+    assert!(
+        stderr(&output).contains("error: evaluation aborted"),
+        "nix stderr wasn't reported: {}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn test_nix_not_found() {
+    let sandbox = Sandbox::isolated();
+
+    let output = sandbox.run(&["--print"]);
+
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    assert!(stderr(&output).contains("is it installed"));
+}
+
+#[test]
+fn test_flake_not_tracked() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix(
+        "echo \"error: path '/nix/store/abc-source/flake.nix' does not exist\" >&2\nexit 1",
+    );
+
+    let output = sandbox.run(&["--print", "--quiet"]);
+
+    assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("hint: did you forget to `git add` flake.nix?"),
+        "no hint: {}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn test_build_failure() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix(
+        "echo \"error: builder for '/nix/store/abc-foo.drv' failed with exit code 2\" >&2\nexit 1",
+    );
+
+    let output = sandbox.run(&["--print"]);
+
+    assert_eq!(output.status.code(), Some(7), "{}", stderr(&output));
+}
+
+#[test]
+fn test_non_utf8_output() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix("printf '\\377\\376'");
+
+    let output = sandbox.run(&["--print"]);
+
+    assert_eq!(output.status.code(), Some(9), "{}", stderr(&output));
+}
+
+#[test]
+fn test_malformed_json() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(r#"{ "bashFunctions": {}, "variables": "#);
+
+    let output = sandbox.run(&["--print"]);
+    assert_eq!(
+        output.status.code(),
+        Some(8),
+        "malformed json was accepted: {}",
+        stderr(&output)
+    );
+
+    // a failed evaluation must not be cached
+    sandbox.stub_nix_json(ENV_JSON);
+
+    let output = sandbox.run(&["--print"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+}
+
+#[test]
+fn test_cache() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path().join("flake.nix"), "{}").unwrap();
+    sandbox.stub_nix_json(ENV_JSON);
+
+    let output = sandbox.run(&["--print"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    sandbox.stub_nix("exit 1");
+
+    let output = sandbox.run(&["--print"]);
+    assert!(output.status.success(), "cached env wasn't used");
+
+    let output = sandbox.run(&["--print", "--refresh"]);
+    assert!(!output.status.success(), "--refresh didn't evaluate nix");
+
+    fs::write(sandbox.path().join("flake.nix"), "{ }").unwrap();
+
+    let output = sandbox.run(&["--print"]);
+    assert!(
+        !output.status.success(),
+        "changed flake.nix didn't invalidate the cache"
+    );
+}
+
+#[test]
+fn test_timeout() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path().join("flake.nix"), "{}").unwrap();
+    sandbox.stub_nix("exec sleep 30");
+
+    let output = sandbox.run(&["--print", "--timeout", "1"]);
+    assert_eq!(output.status.code(), Some(10), "{}", stderr(&output));
+
+    sandbox.stub_nix_json(ENV_JSON);
+    let output = sandbox.run(&["--print"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    // a stale cache entry is better than nothing
+    fs::write(sandbox.path().join("flake.nix"), "{ }").unwrap();
+    sandbox.stub_nix("exec sleep 30");
+
+    let output = sandbox.run(&["--print", "--timeout", "1"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(stdout(&output).contains("var1 = \"value1\""));
+    assert!(
+        stderr(&output).contains("using the last cached env"),
+        "no warning: {}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn test_interrupt() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path().join("flake.nix"), "{}").unwrap();
+    // like nix, fails with an error when it gets the Ctrl-C itself
+    sandbox.stub_nix(&format!(
+        "trap 'echo \"error: interrupted by the user\" >&2; exit 1' INT\ntouch {}\nwhile true; do sleep 0.1; done",
+        sandbox.path().join("started").display()
+    ));
+
+    // in its own process group, like a job in the terminal
+    let child = sandbox
+        .command(sandbox.path(), &["--print"])
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    while !sandbox.path().join("started").exists() {
+        thread::sleep(Duration::from_millis(50));
+    }
+    Command::new("kill")
+        .args(["-INT", "--", &format!("-{}", child.id())])
+        .status()
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(130), "{}", stderr(&output));
+}
+
+#[test]
+fn test_signals_after_nix() {
+    let (sandbox, _) = gc_root_sandbox();
+    // nix is done, the signal handlers for it must not swallow SIGTERM anymore
+    sandbox.stub(
+        "nix-store",
+        &format!(
+            "touch {}\nsleep 5",
+            sandbox.path().join("started").display()
+        ),
+    );
+
+    let child = sandbox.spawn(sandbox.path(), &["--shell", "bash"]);
+    while !sandbox.path().join("started").exists() {
+        thread::sleep(Duration::from_millis(50));
+    }
+    Command::new("kill")
+        .arg(child.id().to_string())
+        .status()
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.signal(), Some(15), "{}", stderr(&output));
+}
+
+#[test]
+fn test_env_file() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix("exit 1");
+    fs::write(sandbox.path().join("env.json"), ENV_JSON).unwrap();
+
+    let output = sandbox.run(&["--print", "--env-file", "env.json"]);
+
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(stdout(&output).contains("var1 = \"value1\""));
+
+    let output = sandbox.run_with_input(
+        sandbox.path(),
+        &["--env-file", "-", "--", "/bin/sh", "-c", "echo \"$var1\""],
+        ENV_JSON,
+    );
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert_eq!(stdout(&output), "value1\n");
+
+    // stdin is used up, an interactive shell would exit right away
+    let output = sandbox.run_with_input(sandbox.path(), &["--env-file", "-"], ENV_JSON);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("only works with --print or -- COMMAND"),
+        "{}",
+        stderr(&output)
+    );
+
+    // one env can't stand in for several dev shells
+    let output = sandbox.run(&["--print", "--env-file", "env.json", ".#a", ".#b"]);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("can't be used with multiple paths"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+/// Stubs `nix` with an env whose `out` is a store path in a fake store,
+/// `nix-store` with a script that records its arguments, and a `bash` that exits.
+fn gc_root_sandbox() -> (Sandbox, PathBuf) {
+    let sandbox = Sandbox::new();
+
+    let store = sandbox.path().join("store");
+    let out = store.join("0a5ybwqb5rn2l3m6ng8rkzs6j3dsb2qi-nix-shell");
+    fs::create_dir_all(&out).unwrap();
+
+    sandbox.stub_nix_json(&format!(
+        r#"
+        {{
+            "bashFunctions": {{}},
+            "variables": {{
+                "NIX_STORE": {{ "type": "exported", "value": "{}" }},
+                "out": {{ "type": "exported", "value": "{}" }}
+            }}
+        }}
+        "#,
+        store.display(),
+        out.display()
+    ));
+    sandbox.stub(
+        "nix-store",
+        &format!(
+            "echo \"$@\" >> {}",
+            sandbox.path().join("nix-store-args").display()
+        ),
+    );
+    sandbox.stub("bash", "exit 0");
+
+    (sandbox, out)
+}
+
+#[test]
+fn test_gc_root() {
+    let (sandbox, out) = gc_root_sandbox();
+
+    let output = sandbox.run(&["--shell", "bash"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    let args =
+        fs::read_to_string(sandbox.path().join("nix-store-args")).expect("nix-store wasn't called");
+    assert!(args.contains("--add-root"), "no gc root added: {}", args);
+    assert!(
+        args.contains(&format!("--realise {}", out.display())),
+        "out isn't rooted: {}",
+        args
+    );
+}
+
+#[test]
+fn test_profile() {
+    let (sandbox, out) = gc_root_sandbox();
+    let profile = sandbox
+        .path()
+        .join("data")
+        .join("nix-dev-env")
+        .join("profiles")
+        .join("dev");
+
+    let output = sandbox.run(&["--print", "--profile", "dev"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    let saved = fs::read_to_string(profile.join("env.json")).expect("env.json wasn't saved");
+    assert!(saved.contains(&out.display().to_string()), "{}", saved);
+    let args =
+        fs::read_to_string(sandbox.path().join("nix-store-args")).expect("nix-store wasn't called");
+    assert!(
+        args.contains(&format!(
+            "--add-root {}/",
+            profile.join("gcroots").display()
+        )),
+        "the profile has no gc root: {}",
+        args
+    );
+
+    // entered without nix
+    sandbox.stub_nix("exit 1");
+    let output = sandbox.run(&["--print", "--from-profile", "dev"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).contains(&out.display().to_string()),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn test_profile_timeout() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path().join("flake.nix"), "{}").unwrap();
+    sandbox.stub_nix_json(ENV_JSON);
+
+    let output = sandbox.run(&["--print", "--profile", "dev"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    // without a cached env the profile is the only fallback
+    fs::remove_dir_all(sandbox.path().join("cache")).unwrap();
+    sandbox.stub_nix("exec sleep 30");
+
+    let output = sandbox.run(&["--print", "--profile", "dev", "--timeout", "1"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(stdout(&output).contains("var1 = \"value1\""));
+    assert!(
+        stderr(&output).contains("using profile dev"),
+        "no warning: {}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn test_gc_root_policy() {
+    let (sandbox, _) = gc_root_sandbox();
+
+    let output = sandbox.run(&["--shell", "bash", "--gc-root", "none"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        !sandbox.path().join("nix-store-args").exists(),
+        "gc root registered with --gc-root none"
+    );
+
+    let output = sandbox.run(&["--shell", "bash", "--gc-root", "remove"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(sandbox.path().join("nix-store-args").exists());
+
+    let gc_roots = sandbox
+        .path()
+        .join("cache")
+        .join("nix-dev-env")
+        .join("gcroots");
+    assert_eq!(
+        fs::read_dir(gc_roots).unwrap().count(),
+        0,
+        "gc root wasn't removed after the shell exited"
+    );
+}
+
+#[test]
+fn test_gc_root_sessions() {
+    let (sandbox, _) = gc_root_sandbox();
+    let gc_roots = sandbox
+        .path()
+        .join("cache")
+        .join("nix-dev-env")
+        .join("gcroots");
+    let started = sandbox.path().join("started");
+
+    // another shell of the same project that is still running
+    sandbox.stub(
+        "bash",
+        &format!("touch {}; exec sleep 30", started.display()),
+    );
+    let mut running = sandbox.spawn(sandbox.path(), &["--shell", "bash", "--gc-root", "remove"]);
+    for _ in 0..100 {
+        if started.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(started.exists(), "the shell didn't start");
+
+    sandbox.stub("bash", "exit 0");
+    let output = sandbox.run(&["--shell", "bash", "--gc-root", "remove"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    let key_dir = fs::read_dir(&gc_roots)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    assert_eq!(
+        fs::read_dir(&key_dir).unwrap().count(),
+        1,
+        "the roots of the running shell were removed"
+    );
+
+    Command::new("kill")
+        .arg(running.id().to_string())
+        .status()
+        .unwrap();
+    running.wait().unwrap();
+    assert_eq!(fs::read_dir(&gc_roots).unwrap().count(), 0);
+
+    // kept roots are only replaced once the new ones exist
+    let listing = sandbox.path().join("listing");
+    let output = sandbox.run(&["--shell", "bash"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    sandbox.stub(
+        "nix-store",
+        &format!("ls -d {}/*/* > {}", gc_roots.display(), listing.display()),
+    );
+    let output = sandbox.run(&["--shell", "bash"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    let listing = fs::read_to_string(listing).unwrap();
+    assert_eq!(listing.lines().count(), 2, "{}", listing);
+    assert_eq!(fs::read_dir(&key_dir).unwrap().count(), 1);
+}
+
+#[test]
+fn test_nix_args() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json_args(ENV_JSON);
+
+    let output = sandbox.run(&[
+        "--print",
+        "--config-str",
+        r#"{ "path_vars": [], "paths": {}, "variables": [], "nix_args": ["--system", "aarch64-linux"] }"#,
+        "--nix-arg",
+        "--impure",
+        "--nix-arg",
+        "--override-input",
+        "--nix-arg",
+        "nixpkgs",
+        "--nix-arg",
+        "path:/src/nixpkgs",
+        ".#ci",
+    ]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("nix-args")).unwrap(),
+        "print-dev-env --json --system aarch64-linux --impure \
+            --override-input nixpkgs path:/src/nixpkgs .#ci\n"
+    );
+}
+
+#[test]
+fn test_legacy_shell_nix() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path().join("shell.nix"), "{ }").unwrap();
+    sandbox.stub_nix_json_args(ENV_JSON);
+
+    let output = sandbox.run(&["--print", "--argstr", "compiler", "gcc13"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("nix-args")).unwrap(),
+        format!(
+            "print-dev-env --json --argstr compiler gcc13 --file {}\n",
+            sandbox.path().join("shell.nix").display()
+        )
+    );
+}
+
+#[test]
+fn test_flake_nix_path() {
+    let sandbox = Sandbox::new();
+    fs::create_dir(sandbox.path().join("sub")).unwrap();
+    fs::write(sandbox.path().join("sub").join("flake.nix"), "{}").unwrap();
+    sandbox.stub_nix_json_args(ENV_JSON);
+
+    let output = sandbox.run(&["--print", "sub/flake.nix#ci"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("nix-args")).unwrap(),
+        "print-dev-env --json ./sub#ci\n"
+    );
+}
+
+#[test]
+fn test_missing_experimental_features() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path().join("nix-features"), "").unwrap();
+    sandbox.stub_nix_json_args(ENV_JSON);
+
+    let output = sandbox.run(&["--print"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("nix-args")).unwrap(),
+        "--extra-experimental-features nix-command flakes print-dev-env --json\n"
+    );
+}
+
+#[test]
+fn test_nix_too_old() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path().join("nix-version"), "nix (Nix) 2.3.16").unwrap();
+    sandbox.stub_nix_json(ENV_JSON);
+
+    let output = sandbox.run(&["--print"]);
+
+    assert!(!output.status.success(), "nix 2.3 was accepted");
+    assert!(
+        stderr(&output).contains("too old"),
+        "no diagnostic for old nix: {}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn test_nix_output() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix(&format!(
+        "echo 'warning: Git tree is dirty' >&2\ncat <<'EOF'\n{}\nEOF",
+        ENV_JSON
+    ));
+
+    let output = sandbox.run(&["--print", "--refresh"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stderr(&output).contains("warning: Git tree is dirty"),
+        "nix warning wasn't shown"
+    );
+
+    let output = sandbox.run(&["--print", "--refresh", "--quiet", "--nix-log", "nix.log"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        !stderr(&output).contains("warning"),
+        "nix output shown with --quiet"
+    );
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("nix.log")).unwrap(),
+        "warning: Git tree is dirty\n"
+    );
+
+    sandbox.stub_nix("echo 'error: evaluation aborted' >&2\nexit 1");
+
+    let output = sandbox.run(&["--print", "--refresh", "--quiet"]);
+    assert!(!output.status.success(), "nix failure was ignored");
+    assert!(
+        stderr(&output).contains("error: evaluation aborted"),
+        "nix error wasn't reported with --quiet"
+    );
+}
+
+#[test]
+fn test_discover_flake() {
+    let sandbox = Sandbox::new();
+    let sub = sandbox.path().join("src").join("module");
+    fs::create_dir_all(&sub).unwrap();
+    fs::create_dir(sandbox.path().join(".git")).unwrap();
+    fs::write(sandbox.path().join("flake.nix"), "{}").unwrap();
+    sandbox.stub_nix_json_args(ENV_JSON);
+
+    let output = sandbox.run_in(&sub, &["--print"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stderr(&output).contains(&format!(
+            "using {}",
+            sandbox.path().join("flake.nix").display()
+        )),
+        "picked file wasn't reported: {}",
+        stderr(&output)
+    );
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("nix-args")).unwrap(),
+        format!("print-dev-env --json {}\n", sandbox.path().display())
+    );
+
+    let output = sandbox.run_in(&sub, &["--print", "--refresh", "--no-discover"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("nix-args")).unwrap(),
+        "print-dev-env --json\n"
+    );
+}
+
+/// Stubs `nix eval` with a flake that has the ci, default and docs devShells.
+fn dev_shells_sandbox() -> Sandbox {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path().join("flake.nix"), "{}").unwrap();
+    sandbox.stub_nix(&format!(
+        r#"
+case "$1" in
+    eval)
+        echo "$@" > {}
+        echo '["ci","default","docs"]'
+        exit 0;;
+esac
+echo "$@" > {}
+cat <<'EOF'
+{}
+EOF"#,
+        sandbox.path().join("eval-args").display(),
+        sandbox.path().join("nix-args").display(),
+        ENV_JSON
+    ));
+
+    sandbox
+}
+
+#[test]
+fn test_list_shells() {
+    let sandbox = dev_shells_sandbox();
+
+    let output = sandbox.run(&["list-shells", "."]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+
+    assert_eq!(stdout(&output), "ci\ndefault\ndocs\n");
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("eval-args")).unwrap(),
+        "eval --json --apply builtins.attrNames .#devShells.x86_64-linux\n"
+    );
+
+    // evaluated like the dev shell itself
+    let output = sandbox.run(&["list-shells", "--nix-arg", "--impure", "."]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("eval-args")).unwrap(),
+        "eval --json --apply builtins.attrNames --impure .#devShells.x86_64-linux\n"
+    );
+
+    sandbox.stub_nix("exec sleep 30");
+    let output = sandbox.run(&["list-shells", "--timeout", "1", "."]);
+    assert_eq!(output.status.code(), Some(10), "{}", stderr(&output));
+}
+
+#[test]
+fn test_select_shell() {
+    let sandbox = dev_shells_sandbox();
+
+    let output = sandbox.run(&[".", "--print", "--dev-shell", "docs"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("nix-args")).unwrap(),
+        "print-dev-env --json .#docs\n"
+    );
+
+    let output = sandbox.run_with_input(
+        sandbox.path(),
+        &[".", "--print", "--select-shell"],
+        "5\n1\n",
+    );
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(stderr(&output).contains("invalid selection: 5"));
+    assert_eq!(
+        fs::read_to_string(sandbox.path().join("nix-args")).unwrap(),
+        "print-dev-env --json .#ci\n"
+    );
+}
+
+#[test]
+fn test_compose_shells() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix(
+        r#"
+case "$*" in
+    *tools*) path=/tools/bin; editor=hx;;
+    *) path=/project/bin; editor=vim;;
+esac
+cat <<EOF
+{
+    "bashFunctions": {},
+    "variables": {
+        "PATH": { "type": "exported", "value": "$path" },
+        "EDITOR": { "type": "exported", "value": "$editor" }
+    }
+}
+EOF"#,
+    );
+
+    let output = sandbox.run(&["--print", "github:me/project", "github:me/tools"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(stdout(&output).contains("PATH = \"/project/bin:/tools/bin\""));
+    assert!(stdout(&output).contains("EDITOR = \"vim\""));
+    assert!(
+        stderr(&output).contains(
+            "variable EDITOR is set by github:me/project and github:me/tools, using github:me/project"
+        ),
+        "conflict wasn't reported: {}",
+        stderr(&output)
+    );
+
+    let output = sandbox.run(&[
+        "--print",
+        "--on-conflict",
+        "last",
+        "github:me/project",
+        "github:me/tools",
+    ]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(stdout(&output).contains("EDITOR = \"hx\""));
+
+    let output = sandbox.run(&[
+        "--print",
+        "--on-conflict",
+        "error",
+        "github:me/project",
+        "github:me/tools",
+    ]);
+    assert!(!output.status.success(), "conflict didn't fail");
+}
+
+#[test]
+fn test_bash_functions() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(
+        r#"
+{
+    "bashFunctions": { "greet": "    echo \"hello $1\"" },
+    "variables": {}
+}
+"#,
+    );
+    fs::write(sandbox.path().join(".bashrc"), "echo bashrc sourced").unwrap();
+
+    // not a sh stub, sh may drop the BASH_FUNC_ variables
+    let bash = sandbox.path().join("shell").join("bash");
+    fs::create_dir(bash.parent().unwrap()).unwrap();
+    let stub_bash = |script: &str| {
+        fs::write(&bash, format!("#!/usr/bin/env bash\n{}\n", script)).unwrap();
+        fs::set_permissions(&bash, fs::Permissions::from_mode(0o755)).unwrap();
+    };
+    let args = ["--shell", bash.to_str().unwrap(), "--gc-root", "none"];
+
+    // runs the rcfile like an interactive bash would
+    stub_bash(r#"[ "$1" = --rcfile ] && source "$2"; greet world"#);
+
+    let output = sandbox.run(&args);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).contains("bashrc sourced"),
+        "{}",
+        stdout(&output)
+    );
+    assert!(
+        stdout(&output).contains("hello world"),
+        "{}",
+        stdout(&output)
+    );
+
+    stub_bash("greet child");
+
+    let output = sandbox.run(&[&args[..], &["--export-functions"]].concat());
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).contains("hello child"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn test_build_home() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(
+        r#"
+{
+    "bashFunctions": {},
+    "variables": {
+        "HOME": { "type": "exported", "value": "/homeless-shelter" },
+        "var1": { "type": "exported", "value": "value1" }
+    }
+}
+"#,
+    );
+    fs::write(sandbox.path().join(".bashrc"), "echo bashrc sourced").unwrap();
+    let bash = sandbox.path().join("shell").join("bash");
+    fs::create_dir(bash.parent().unwrap()).unwrap();
+    fs::write(
+        &bash,
+        "#!/usr/bin/env bash\n[ \"$1\" = --rcfile ] && source \"$2\"; echo \"HOME=$HOME $var1\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&bash, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = sandbox.run(&["--shell", bash.to_str().unwrap(), "--gc-root", "none"]);
+
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).contains("bashrc sourced"),
+        "{}",
+        stdout(&output)
+    );
+    assert!(
+        stdout(&output).contains(&format!("HOME={} value1", sandbox.path().display())),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn test_arrays() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(
+        r#"
+{
+    "bashFunctions": {},
+    "variables": {
+        "buildInputs": { "type": "array", "value": ["/nix/store/a b", "it's"] },
+        "outputs": { "type": "associative", "value": { "out": "/nix/store/out" } }
+    }
+}
+"#,
+    );
+
+    let shells = sandbox.path().join("shell");
+    fs::create_dir(&shells).unwrap();
+    let stub_shell = |name: &str, script: &str| {
+        let file = shells.join(name);
+        fs::write(&file, format!("#!/usr/bin/env bash\n{}\n", script)).unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+        file.to_str().unwrap().to_string()
+    };
+
+    let bash = stub_shell(
+        "bash",
+        r#"source "$2"; echo "${#buildInputs[@]} ${buildInputs[1]} ${outputs[out]}""#,
+    );
+    let output = sandbox.run(&["--shell", &bash, "--gc-root", "none"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).contains("2 it's /nix/store/out"),
+        "{}",
+        stdout(&output)
+    );
+
+    // prints the init script instead of sourcing it
+    let fish = stub_shell("fish", r#"eval "cat ${2#source }""#);
+    let output = sandbox.run(&["--shell", &fish, "--gc-root", "none"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).contains(r"set -g buildInputs '/nix/store/a b' 'it\'s'"),
+        "{}",
+        stdout(&output)
+    );
+    assert!(
+        stderr(&output).contains("skipping outputs"),
+        "no warning: {}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn test_shell_hook() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(
+        r#"
+{
+    "bashFunctions": {},
+    "variables": {
+        "shellHook": { "type": "var", "value": "echo hook ran" }
+    }
+}
+"#,
+    );
+
+    let bash = sandbox.path().join("shell").join("bash");
+    fs::create_dir(bash.parent().unwrap()).unwrap();
+    fs::write(
+        &bash,
+        "#!/usr/bin/env bash\n[ \"$1\" = --rcfile ] && source \"$2\"\necho started\n",
+    )
+    .unwrap();
+    fs::set_permissions(&bash, fs::Permissions::from_mode(0o755)).unwrap();
+    let args = ["--shell", bash.to_str().unwrap(), "--gc-root", "none"];
+
+    let output = sandbox.run(&args);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(stdout(&output).contains("hook ran"), "{}", stdout(&output));
+
+    let output = sandbox.run(&[&args[..], &["--no-shell-hook"]].concat());
+    assert!(stdout(&output).contains("started"), "{}", stderr(&output));
+    assert!(!stdout(&output).contains("hook ran"), "hook wasn't skipped");
+
+    let config = format!(
+        r#"{{ "path_vars": [], "paths": {{}}, "variables": [], "shell_hooks": {{ "{}": false }} }}"#,
+        sandbox.path().display()
+    );
+    let output = sandbox.run(&[&args[..], &["--config-str", &config]].concat());
+    assert!(stdout(&output).contains("started"), "{}", stderr(&output));
+    assert!(
+        !stdout(&output).contains("hook ran"),
+        "config didn't deny the hook"
+    );
+}
+
+#[test]
+fn test_exec() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(ENV_JSON);
+
+    let output = sandbox.run(&["--", "sh", "-c", "echo \"$var1\"; exit 3"]);
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "value1\n");
+
+    let output = sandbox.run(&["--", "does-not-exist"]);
+    assert!(
+        stderr(&output).contains("failed to run does-not-exist"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn test_exec_forwards_signals() {
+    let (sandbox, _) = gc_root_sandbox();
+
+    // with --gc-root remove the command runs as a child that gets SIGTERM forwarded
+    let child = sandbox.spawn(
+        sandbox.path(),
+        &[
+            "--gc-root",
+            "remove",
+            "--",
+            "sh",
+            "-c",
+            "trap 'echo terminated; exit 5' TERM; touch started; while true; do sleep 0.1; done",
+        ],
+    );
+
+    while !sandbox.path().join("started").exists() {
+        thread::sleep(Duration::from_millis(50));
+    }
+    Command::new("kill")
+        .arg(child.id().to_string())
+        .status()
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "terminated\n");
+}
+
+#[test]
+fn test_pure() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(ENV_JSON);
+    let script = "echo \"$HOME|$XDG_CACHE_HOME|$XDG_DATA_HOME|$PATH|$var1\"";
+
+    let output = sandbox.run(&["--pure", "--", "/bin/sh", "-c", script]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        format!("{}|||/dev/bin|value1\n", sandbox.path().display())
+    );
+
+    let config = r#"{ "path_vars": [], "paths": {}, "variables": [], "keep": ["XDG_DATA_HOME"] }"#;
+    let output = sandbox.run(&[
+        "--ignore-environment",
+        "--config-str",
+        config,
+        "--",
+        "/bin/sh",
+        "-c",
+        script,
+    ]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "{}||{}|/dev/bin|value1\n",
+            sandbox.path().display(),
+            sandbox.path().join("data").display()
+        )
+    );
+}
+
+#[test]
+fn test_pure_shell() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(ENV_JSON);
+    sandbox.stub("bash", "echo \"shell started: $PATH\"");
+
+    // found on the inherited PATH, the dev PATH doesn't have it
+    let output = sandbox.run(&["--pure", "--shell", "bash", "--gc-root", "none"]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).contains("shell started: /dev/bin\n"),
+        "{}",
+        stdout(&output)
+    );
+
+    let bash = sandbox.path().join("bin").join("bash");
+    let output = sandbox.run_with_env(
+        &["--pure", "--gc-root", "none"],
+        &[("SHELL", bash.to_str().unwrap())],
+    );
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).contains("shell started: /dev/bin\n"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn test_path_precedence() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(ENV_JSON);
+    let bin = sandbox.path().join("bin");
+    let bin = bin.to_str().unwrap();
+
+    let output = sandbox.run(&["--", "/bin/sh", "-c", "echo \"$PATH\""]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).starts_with(&format!("/dev/bin:{}:", bin)),
+        "dev paths don't come first: {}",
+        stdout(&output)
+    );
+
+    let config = |precedence: &str| {
+        format!(
+            r#"{{ "path_vars": [], "paths": {{}}, "variables": [], "path_precedence": {{ "PATH": "{}" }} }}"#,
+            precedence
+        )
+    };
+
+    let output = sandbox.run(&[
+        "--config-str",
+        &config("append"),
+        "--",
+        "/bin/sh",
+        "-c",
+        "echo \"$PATH\"",
+    ]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(stdout(&output).starts_with(&format!("{}:", bin)));
+    assert!(stdout(&output).ends_with(":/dev/bin\n"));
+
+    let output = sandbox.run(&[
+        "--config-str",
+        &config("replace"),
+        "--",
+        "/bin/sh",
+        "-c",
+        "echo \"$PATH\"",
+    ]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert_eq!(stdout(&output), "/dev/bin\n");
+}
+
+#[test]
+fn test_path_precedence_rcfile() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(ENV_JSON);
+    fs::write(
+        sandbox.path().join(".bashrc"),
+        "export PATH=/usr/local/gcc-host/bin:$PATH",
+    )
+    .unwrap();
+    let bash = sandbox.path().join("shell").join("bash");
+    fs::create_dir(bash.parent().unwrap()).unwrap();
+    fs::write(
+        &bash,
+        "#!/usr/bin/env bash\n[ \"$1\" = --rcfile ] && source \"$2\"; echo \"PATH=$PATH\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&bash, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = sandbox.run(&["--shell", bash.to_str().unwrap(), "--gc-root", "none"]);
+
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).contains("\nPATH=/dev/bin:/usr/local/gcc-host/bin:"),
+        "the dev paths don't come first: {}",
+        stdout(&output)
+    );
+    assert_eq!(
+        stdout(&output).matches("/dev/bin").count(),
+        1,
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn test_separators() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(
+        r#"
+{
+    "bashFunctions": {},
+    "variables": {
+        "NIX_CFLAGS_COMPILE": { "type": "exported", "value": "-I/good -I/bad -O2" }
+    }
+}
+"#,
+    );
+    let config = r#"{
+        "path_vars": [],
+        "separators": { "NIX_CFLAGS_COMPILE": " " },
+        "paths": { "NIX_CFLAGS_COMPILE": ["-I/bad"] },
+        "variables": []
+    }"#;
+
+    let output = sandbox.run(&[
+        "--config-str",
+        config,
+        "--pure",
+        "--",
+        "/bin/sh",
+        "-c",
+        "echo \"$NIX_CFLAGS_COMPILE\"",
+    ]);
+
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert_eq!(stdout(&output), "-I/good -O2\n");
+}
+
+#[test]
+fn test_separators_repeated_flags() {
+    let sandbox = Sandbox::new();
+    sandbox.stub_nix_json(
+        r#"
+{
+    "bashFunctions": {},
+    "variables": {
+        "NIX_CFLAGS_COMPILE": { "type": "exported", "value": "-isystem /a/include -isystem /b/include" }
+    }
+}
+"#,
+    );
+    let config = r#"{
+        "path_vars": [],
+        "separators": { "NIX_CFLAGS_COMPILE": " " },
+        "paths": {},
+        "variables": []
+    }"#;
+
+    let output = sandbox.run(&[
+        "--config-str",
+        config,
+        "--",
+        "/bin/sh",
+        "-c",
+        "echo \"$NIX_CFLAGS_COMPILE\"",
+    ]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert_eq!(stdout(&output), "-isystem /a/include -isystem /b/include\n");
+
+    // the init script runs after .bashrc changed the flags
+    fs::write(
+        sandbox.path().join(".bashrc"),
+        "export NIX_CFLAGS_COMPILE=-O2",
+    )
+    .unwrap();
+    let bash = sandbox.path().join("shell").join("bash");
+    fs::create_dir(bash.parent().unwrap()).unwrap();
+    fs::write(
+        &bash,
+        "#!/usr/bin/env bash\n[ \"$1\" = --rcfile ] && source \"$2\"; echo \"$NIX_CFLAGS_COMPILE\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&bash, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = sandbox.run(&[
+        "--config-str",
+        config,
+        "--shell",
+        bash.to_str().unwrap(),
+        "--gc-root",
+        "none",
+    ]);
+    assert!(output.status.success(), "failed: {}", stderr(&output));
+    assert!(
+        stdout(&output).ends_with("\n-isystem /a/include -isystem /b/include -O2\n"),
+        "{}",
+        stdout(&output)
+    );
+}